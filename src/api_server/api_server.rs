@@ -1,34 +1,103 @@
+use actix_web::http::StatusCode;
 use actix_web::{web, App, HttpResponse, HttpServer};
-use log::{debug, error, info, trace, warn};
-use serde::{Deserialize, Serialize};
+use log::{debug, info};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use crate::blockchain::block_and_blockchain::BlockChain;
-use crate::wallet::wallet::Wallet;
+use crate::blockchain::block_tree::BlockAcceptance;
+use crate::blockchain::chain_params::ChainParams;
+use crate::blockchain::mempool::MempoolRejection;
+use crate::blockchain::miner::MinerHandle;
+use crate::blockchain::transaction::BlockTransaction;
+use crate::blockchain::validation::BlockChainError;
+use crate::wallet::wallet::{Transaction as WalletTransaction, Wallet};
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct ApiServer {
     port: u16,
-    cache: HashMap<String, BlockChain>,
+    // Requests are served from several threads, only one of them may change the chain
+    cache: HashMap<String, Mutex<BlockChain>>,
+    // Cancels the block being mined, without waiting for the chain
+    miner: MinerHandle,
 }
 
 impl ApiServer {
-    pub fn new(port: u16) -> Self {
+    const DATA_DIR: &str = "data";
+    const MINER_KEY_FILE: &str = "miner.key";
+
+    pub fn new(port: u16, params: ChainParams) -> Self {
+        // Every network keeps its blocks and its miner key apart
+        let dir = Path::new(ApiServer::DATA_DIR).join(params.name);
+        let miner_wallet = ApiServer::miner_wallet(&dir, &params);
+        let blockchain = BlockChain::open(miner_wallet.get_address(), params, dir)
+            .expect("Error loading the blockchain");
         let mut api_server = Self {
             port,
             cache: HashMap::new(),
+            miner: blockchain.miner_handle(),
         };
-        let miner_wallet = Wallet::new();
-        api_server.cache.insert(
-            "Blockchain".to_string(),
-            BlockChain::new(miner_wallet.get_address()),
-        );
         api_server
+            .cache
+            .insert("Blockchain".to_string(), Mutex::new(blockchain));
+        api_server
+    }
+
+    // The wallet the rewards are paid to, kept in `dir` so they stay spendable after a
+    // restart. Only the owner may read the key.
+    fn miner_wallet(dir: &Path, params: &ChainParams) -> Wallet {
+        let path = dir.join(ApiServer::MINER_KEY_FILE);
+        if let Ok(private_key) = fs::read_to_string(&path) {
+            return Wallet::from_private_key(&private_key, params)
+                .expect("Error reading the miner key");
+        }
+        let wallet = Wallet::new(params);
+        fs::create_dir_all(dir).expect("Error creating the data directory");
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(&path)
+            .and_then(|mut file| file.write_all(wallet.private_key_str().as_bytes()))
+            .expect("Error writing the miner key");
+        println!("Created miner key for {}", wallet.get_address());
+        wallet
+    }
+
+    fn blockchain(&self) -> MutexGuard<'_, BlockChain> {
+        self.cache.get("Blockchain").unwrap().lock().unwrap()
+    }
+
+    // Status code telling a client why the chain turned its request down
+    fn error_response(error: &BlockChainError) -> HttpResponse {
+        let status = match error {
+            BlockChainError::InvalidSignature => StatusCode::BAD_REQUEST,
+            BlockChainError::SelfSend => StatusCode::FORBIDDEN,
+            BlockChainError::NonceAlreadyUsed { .. }
+            | BlockChainError::ConflictingSpend { .. }
+            | BlockChainError::Mempool(MempoolRejection::Duplicate) => StatusCode::CONFLICT,
+            BlockChainError::NonceTooHigh { .. }
+            | BlockChainError::InsufficientBalance { .. }
+            | BlockChainError::Mempool(MempoolRejection::FeeTooLow { .. })
+            | BlockChainError::InvalidTransaction(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            BlockChainError::Mempool(MempoolRejection::TooLarge { .. }) => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            BlockChainError::Mempool(MempoolRejection::PoolFull)
+            | BlockChainError::MiningCancelled => StatusCode::SERVICE_UNAVAILABLE,
+            BlockChainError::InvalidBlock { .. } | BlockChainError::Storage(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        HttpResponse::build(status).json(serde_json::json!({ "error": error.to_string() }))
     }
 
     async fn get_index(&self) -> HttpResponse {
-        let blockchain = self.cache.get("Blockchain").unwrap();
+        let blockchain = self.blockchain();
         let first_block = blockchain[0].clone();
         let block_json = serde_json::to_string(&first_block).unwrap();
         debug!("Block Json: {:?}", block_json);
@@ -41,19 +110,218 @@ impl ApiServer {
         data.get_ref().get_index().await
     }
 
-    pub async fn run(&self) {
-        let api = Arc::new(self.clone());
+    fn transaction_json(transaction: &BlockTransaction) -> serde_json::Value {
+        match transaction {
+            BlockTransaction::Account(tx) => serde_json::json!({
+                "sender": String::from_utf8_lossy(&tx.sender_address),
+                "recipient": String::from_utf8_lossy(&tx.recipient_address),
+                "value": tx.value,
+                "fee": tx.fee,
+                "nonce": tx.nonce,
+                "public_key": hex::encode(&tx.public_key),
+            }),
+            BlockTransaction::Utxo(tx) => serde_json::json!({
+                "coinbase": tx.is_coinbase(),
+                "inputs": tx.inputs.iter().map(|input| serde_json::json!({
+                    "txid": hex::encode(&input.previous_output.txid),
+                    "index": input.previous_output.index,
+                })).collect::<Vec<_>>(),
+                "outputs": tx.outputs.iter().map(|output| serde_json::json!({
+                    "address": String::from_utf8_lossy(&output.address),
+                    "value": output.value,
+                })).collect::<Vec<_>>(),
+            }),
+        }
+    }
+
+    async fn get_transaction(&self, txid: &str) -> HttpResponse {
+        let blockchain = self.blockchain();
+        let Ok(txid_bin) = hex::decode(txid) else {
+            return HttpResponse::BadRequest().body("The transaction id must be hex encoded");
+        };
+        match blockchain.get_transaction(&txid_bin) {
+            Some(lookup) => {
+                let mut body = ApiServer::transaction_json(&lookup.transaction);
+                body["txid"] = txid.into();
+                body["block_height"] = lookup.block_height.into();
+                body["block_hash"] = hex::encode(&lookup.block_hash).into();
+                body["confirmations"] = lookup.confirmations.into();
+                HttpResponse::Ok().json(body)
+            }
+            None => HttpResponse::NotFound().body("Transaction not found"),
+        }
+    }
+
+    pub async fn get_transaction_handler(
+        data: web::Data<Arc<ApiServer>>,
+        path: web::Path<String>,
+    ) -> HttpResponse {
+        info!("Receiving request at /transactions/{}", path);
+        data.get_ref().get_transaction(&path).await
+    }
+
+    async fn get_mempool(&self) -> HttpResponse {
+        let mut blockchain = self.blockchain();
+        let stats = blockchain.mempool_stats();
+        HttpResponse::Ok().json(serde_json::json!({
+            "count": stats.count,
+            "bytes": stats.bytes,
+            "oldest_seconds": stats.oldest.map(|age| age.as_secs()),
+        }))
+    }
+
+    pub async fn get_mempool_handler(data: web::Data<Arc<ApiServer>>) -> HttpResponse {
+        info!("Receiving request at /mempool");
+        data.get_ref().get_mempool().await
+    }
+
+    async fn get_supply(&self, height: u64) -> HttpResponse {
+        let blockchain = self.blockchain();
+        match blockchain.circulating_supply(height) {
+            Some(supply) => HttpResponse::Ok().json(serde_json::json!({
+                "height": height,
+                "circulating_supply": supply,
+                "max_supply": blockchain.params().max_supply,
+            })),
+            None => HttpResponse::NotFound().body("No block at this height"),
+        }
+    }
+
+    pub async fn get_supply_handler(
+        data: web::Data<Arc<ApiServer>>,
+        path: web::Path<u64>,
+    ) -> HttpResponse {
+        info!("Receiving request at /supply/{}", path);
+        data.get_ref().get_supply(*path).await
+    }
+
+    async fn post_transaction(&self, transaction: &WalletTransaction) -> HttpResponse {
+        match self.blockchain().add_transaction(transaction) {
+            Ok(()) => HttpResponse::Accepted().json(serde_json::json!({
+                "sender": transaction.sender,
+                "nonce": transaction.nonce,
+            })),
+            Err(error) => ApiServer::error_response(&error),
+        }
+    }
+
+    pub async fn post_transaction_handler(
+        data: web::Data<Arc<ApiServer>>,
+        body: web::Json<WalletTransaction>,
+    ) -> HttpResponse {
+        info!("Receiving request at POST /transactions");
+        data.get_ref().post_transaction(&body).await
+    }
+
+    async fn post_mine(&self) -> HttpResponse {
+        // 1. Assemble the block, then let go of the chain for the search so other requests,
+        // and a new tip cancelling it, are not held up
+        let (mut block, miner) = {
+            let mut blockchain = self.blockchain();
+            match blockchain.block_template() {
+                Ok(block) => (block, blockchain.miner().clone()),
+                Err(error) => return ApiServer::error_response(&error),
+            }
+        };
+        // 2. The search blocks, it runs on a thread of its own
+        let mined = web::block(move || BlockChain::mine_block(&miner, &mut block).map(|_| block));
+        let block = match mined.await {
+            Ok(Ok(block)) => block,
+            Ok(Err(error)) => return ApiServer::error_response(&error),
+            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+        };
+        // 3. The block goes through the same checks as one mined elsewhere
+        let mut blockchain = self.blockchain();
+        let accepted = match blockchain.accept_block(block) {
+            Ok(accepted) => accepted,
+            Err(error) => return ApiServer::error_response(&error),
+        };
+        match accepted {
+            BlockAcceptance::Extended | BlockAcceptance::Reorganized { .. } => {
+                HttpResponse::Created().json(serde_json::json!({
+                    "height": blockchain.height(),
+                    "hash": hex::encode(blockchain.last_block().hash()),
+                }))
+            }
+            BlockAcceptance::Rejected(reason) => {
+                ApiServer::error_response(&BlockChainError::InvalidBlock {
+                    height: blockchain.height() + 1,
+                    reason,
+                })
+            }
+            // The tip moved on while the block was found, it is of no use anymore
+            BlockAcceptance::SideBranch | BlockAcceptance::AlreadyKnown => {
+                ApiServer::error_response(&BlockChainError::MiningCancelled)
+            }
+        }
+    }
+
+    pub async fn post_mine_handler(data: web::Data<Arc<ApiServer>>) -> HttpResponse {
+        info!("Receiving request at POST /mine");
+        data.get_ref().post_mine().await
+    }
+
+    async fn get_validate(&self) -> HttpResponse {
+        match self.blockchain().validate_chain().into_result() {
+            Ok(blocks) => HttpResponse::Ok().json(serde_json::json!({ "blocks_checked": blocks })),
+            Err(error) => ApiServer::error_response(&error),
+        }
+    }
+
+    pub async fn get_validate_handler(data: web::Data<Arc<ApiServer>>) -> HttpResponse {
+        info!("Receiving request at /validate");
+        data.get_ref().get_validate().await
+    }
+
+    async fn get_blocks(&self) -> HttpResponse {
+        let mut bytes = Vec::<u8>::new();
+        match self.blockchain().export_blocks(&mut bytes) {
+            Ok(()) => HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .body(bytes),
+            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        }
+    }
+
+    pub async fn get_blocks_handler(data: web::Data<Arc<ApiServer>>) -> HttpResponse {
+        info!("Receiving request at /blocks");
+        data.get_ref().get_blocks().await
+    }
+
+    pub async fn run(self) {
+        let port = self.port;
+        // The server waits for running requests before it stops, a search included
+        let miner = self.miner.clone();
+        actix_web::rt::spawn(async move {
+            if actix_web::rt::signal::ctrl_c().await.is_ok() {
+                miner.cancel();
+            }
+        });
+        let api = Arc::new(self);
         let server = HttpServer::new(move || {
             App::new()
                 .app_data(web::Data::new(api.clone()))
                 .wrap(actix_web::middleware::Logger::default())
                 .route("/", web::get().to(Self::get_index_handler))
+                .route(
+                    "/transactions",
+                    web::post().to(Self::post_transaction_handler),
+                )
+                .route(
+                    "/transactions/{txid}",
+                    web::get().to(Self::get_transaction_handler),
+                )
+                .route("/mempool", web::get().to(Self::get_mempool_handler))
+                .route("/supply/{height}", web::get().to(Self::get_supply_handler))
+                .route("/mine", web::post().to(Self::post_mine_handler))
+                .route("/blocks", web::get().to(Self::get_blocks_handler))
+                .route("/validate", web::get().to(Self::get_validate_handler))
         });
 
-        println!("Server running on port: {}", self.port);
+        println!("Server running on port: {}", port);
 
         server
-            .bind(("0.0.0.0", self.port))
+            .bind(("0.0.0.0", port))
             .expect("Error binding to the port")
             .run()
             .await
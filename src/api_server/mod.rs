@@ -1,2 +1,2 @@
-
-pub mod api_server;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod api_server;
@@ -1,50 +1,153 @@
+use crate::blockchain::block_index::BlockIndex;
+use crate::blockchain::block_stream::{BlockReader, BlockWriter};
+use crate::blockchain::block_tree::{BlockAcceptance, BlockTree};
+use crate::blockchain::chain_params::ChainParams;
+use crate::blockchain::chain_state::{ChainState, StateUndo};
+use crate::blockchain::encoding::{
+    finish, read_bytes, read_i32, read_u128, read_u32, read_u8, write_bytes, DecodeError,
+    MAX_FIELD_LENGTH, MAX_TRANSACTION_LENGTH,
+};
+use crate::blockchain::mempool::{Mempool, MempoolEntry, MempoolRejection, MempoolStats};
+use crate::blockchain::merkle::{self, MerkleProof};
+use crate::blockchain::miner::{Miner, MinerHandle, MiningOutcome};
+use crate::blockchain::storage::BlockStore;
+use crate::blockchain::target::Target;
 use crate::blockchain::transaction::*;
+use crate::blockchain::utxo::{OutPoint, TxOutput, UtxoTransaction};
+use crate::blockchain::validation::{BlockChainError, ChainValidationError, ChainValidationReport};
 use crate::wallet::wallet::{Transaction as WalletTransaction, Wallet};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::cmp::PartialEq;
+use std::cmp::{Ordering, PartialEq, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io::{self, Read, Write};
 use std::ops::{AddAssign, Index};
-use std::time::{Instant, SystemTime};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
 
 pub trait Serialization<T> {
     fn serialization(&self) -> Vec<u8>;
-    fn deserialization(bytes: Vec<u8>) -> T;
+    // Fails instead of panicking on truncated, oversized or otherwise malformed input
+    fn deserialization(bytes: Vec<u8>) -> Result<T, DecodeError>;
 }
 
+#[allow(clippy::enum_variant_names)]
 pub enum BlockSearch {
     SearchByIndex(usize),
     SearchByPreviousHash(Vec<u8>),
     SearchByBlockHash(Vec<u8>),
     SearchByNonce(i32),
     SearchByTimestamp(u128),
+    // Every block stamped between the two timestamps, both included
+    SearchByTimestampRange(u128, u128),
     SearchByTransaction(Vec<u8>),
+    SearchByTransactionId(Vec<u8>),
 }
 
+#[derive(Debug)]
 pub enum BlockSearchResult<'a> {
     Success(&'a Block),
+    // Every block matching a search that can match more than one block, in chain order
+    Matches(Vec<&'a Block>),
     FailOfEmptyBlocks,
     FailOfIndex(usize),
     FailOfPreviousHash(Vec<u8>),
     FailOfBlockHash(Vec<u8>),
     FailOfNonce(i32),
     FailOfTimestamp(u128),
+    FailOfTimestampRange(u128, u128),
     FailOfTransaction(Vec<u8>),
+    FailOfTransactionId(Vec<u8>),
 }
 
+/// A transaction handed to `BlockChain::add_transaction`, of either kind.
+pub enum PendingTransaction<'a> {
+    Account(&'a WalletTransaction),
+    Utxo(&'a UtxoTransaction),
+}
+
+impl<'a> From<&'a WalletTransaction> for PendingTransaction<'a> {
+    fn from(tx: &'a WalletTransaction) -> Self {
+        PendingTransaction::Account(tx)
+    }
+}
+
+impl<'a> From<&'a UtxoTransaction> for PendingTransaction<'a> {
+    fn from(tx: &'a UtxoTransaction) -> Self {
+        PendingTransaction::Utxo(tx)
+    }
+}
+
+/// The part of a block that is hashed and mined. The transactions are only
+/// committed to through `merkle_root`, so mining cost does not depend on the
+/// size of the block and headers can be synced on their own.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Block {
-    nonce: i32,
+pub struct BlockHeader {
+    version: u32,
     previous_hash: Vec<u8>,
+    merkle_root: Vec<u8>,
     time_stamp: u128,
-    transactions: Vec<Vec<u8>>,
+    // Compact encoding of the proof of work target this block was mined at
+    bits: u32,
+    nonce: i32,
 }
 
-impl AddAssign<i32> for Block {
+impl AddAssign<i32> for BlockHeader {
     fn add_assign(&mut self, rhs: i32) {
         self.nonce += rhs;
     }
 }
 
+impl BlockHeader {
+    pub const VERSION: u32 = 1;
+
+    pub fn hash(&self) -> Vec<u8> {
+        self.hash_bytes().to_vec()
+    }
+
+    // Feeds the fields straight into the hasher so the mining loop does not allocate
+    pub(crate) fn hash_bytes(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.version.to_be_bytes());
+        hasher.update(&self.previous_hash);
+        hasher.update(&self.merkle_root);
+        hasher.update(self.time_stamp.to_be_bytes());
+        hasher.update(self.bits.to_be_bytes());
+        hasher.update(self.nonce.to_be_bytes());
+        hasher.finalize().into()
+    }
+
+    pub fn previous_hash(&self) -> &[u8] {
+        &self.previous_hash
+    }
+
+    pub fn merkle_root(&self) -> &[u8] {
+        &self.merkle_root
+    }
+
+    pub fn time_stamp(&self) -> u128 {
+        self.time_stamp
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn nonce(&self) -> i32 {
+        self.nonce
+    }
+
+    pub fn set_nonce(&mut self, nonce: i32) {
+        self.nonce = nonce;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Block {
+    header: BlockHeader,
+    transactions: Vec<Vec<u8>>,
+}
+
 impl PartialEq for Block {
     fn eq(&self, other: &Self) -> bool {
         let self_hash = self.hash();
@@ -54,83 +157,676 @@ impl PartialEq for Block {
 }
 
 impl Block {
-    pub fn new(nonce: i32, previous_hash: Vec<u8>) -> Self {
+    pub fn new(nonce: i32, previous_hash: Vec<u8>, bits: u32) -> Self {
         let time_now = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap();
         Self {
-            nonce,
-            previous_hash,
-            time_stamp: time_now.as_nanos(),
+            header: BlockHeader {
+                version: BlockHeader::VERSION,
+                previous_hash,
+                merkle_root: merkle::merkle_root(&[]),
+                time_stamp: time_now.as_nanos(),
+                bits,
+                nonce,
+            },
             transactions: Vec::<Vec<u8>>::new(),
         }
     }
 
+    // The first block of the chain. It is fixed by the network parameters so that every
+    // node starts from the same one, and it is not mined.
+    pub fn genesis(params: &ChainParams) -> Self {
+        let mut transactions = Vec::<Vec<u8>>::new();
+        if !params.premine.is_empty() {
+            let outputs = params
+                .premine
+                .iter()
+                .map(|(address, value)| TxOutput::new(*value, address.as_bytes().to_vec()))
+                .collect();
+            transactions.push(UtxoTransaction::coinbase(0, outputs).serialization());
+        }
+        Self {
+            header: BlockHeader {
+                version: BlockHeader::VERSION,
+                previous_hash: vec![0_u8; 32],
+                merkle_root: merkle::merkle_root(&transactions),
+                time_stamp: params.genesis_time_stamp,
+                bits: params.initial_bits,
+                nonce: 0,
+            },
+            transactions,
+        }
+    }
+
     pub fn print(&self) {
         // Formating value as hex
-        println!("Timestamp: {:x}", self.time_stamp);
+        println!("Timestamp: {:x}", self.header.time_stamp);
         // Formating value as integer
-        println!("Nonce: {}", self.nonce);
+        println!("Version: {}", self.header.version);
+        println!("Nonce: {}", self.header.nonce);
+        println!("Bits: {:#010x}", self.header.bits);
         // Using Debug formatter for complex values
-        println!("previous_hash: {:?}", self.previous_hash);
+        println!("previous_hash: {:?}", self.header.previous_hash);
+        println!("merkle_root: {:?}", self.header.merkle_root);
         println!("Transactions: {:?}", self.transactions);
         for (idx, tx) in self.transactions.iter().enumerate() {
-            let transaction = Transaction::deserialization(tx.to_vec());
+            let transaction = BlockTransaction::decode(tx);
             println!("The transaction: {} is number: {}", transaction, idx)
         }
     }
 
+    // A block is identified by the hash of its header alone
     pub fn hash(&self) -> Vec<u8> {
+        self.header.hash()
+    }
+
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    pub fn merkle_root(&self) -> &[u8] {
+        &self.header.merkle_root
+    }
+
+    pub fn transactions(&self) -> &[Vec<u8>] {
+        &self.transactions
+    }
+}
+
+impl Serialization<BlockHeader> for BlockHeader {
+    fn serialization(&self) -> Vec<u8> {
         let mut bin = Vec::<u8>::new();
-        bin.extend(self.nonce.to_be_bytes());
-        bin.extend(self.previous_hash.clone());
+        bin.extend(self.version.to_be_bytes());
+        write_bytes(&mut bin, &self.previous_hash);
+        write_bytes(&mut bin, &self.merkle_root);
         bin.extend(self.time_stamp.to_be_bytes());
+        bin.extend(self.bits.to_be_bytes());
+        bin.extend(self.nonce.to_be_bytes());
+        bin
+    }
+
+    fn deserialization(bytes: Vec<u8>) -> Result<BlockHeader, DecodeError> {
+        let mut pos = 0;
+        let header = BlockHeader::read(&bytes, &mut pos)?;
+        finish(&bytes, pos)?;
+        Ok(header)
+    }
+}
+
+impl BlockHeader {
+    fn read(bytes: &[u8], pos: &mut usize) -> Result<BlockHeader, DecodeError> {
+        Ok(BlockHeader {
+            version: read_u32(bytes, pos)?,
+            previous_hash: read_bytes(bytes, pos, MAX_FIELD_LENGTH)?,
+            merkle_root: read_bytes(bytes, pos, MAX_FIELD_LENGTH)?,
+            time_stamp: read_u128(bytes, pos)?,
+            bits: read_u32(bytes, pos)?,
+            nonce: read_i32(bytes, pos)?,
+        })
+    }
+}
+
+impl Block {
+    // First byte of a serialized block, bumped whenever the layout below changes
+    pub const FORMAT_VERSION: u8 = 1;
+}
+
+impl Serialization<Block> for Block {
+    fn serialization(&self) -> Vec<u8> {
+        // 1. The format version and the header
+        let mut bin = vec![Block::FORMAT_VERSION];
+        bin.extend(self.header.serialization());
+        // 2. The transactions, each one already serialized
+        bin.extend((self.transactions.len() as u32).to_be_bytes());
         for tx in self.transactions.iter() {
-            bin.extend(tx);
+            write_bytes(&mut bin, tx);
         }
-        let mut hasher = Sha256::new();
-        hasher.update(bin);
-        hasher.finalize().to_vec()
+        bin
+    }
+
+    fn deserialization(bytes: Vec<u8>) -> Result<Block, DecodeError> {
+        let mut pos = 0;
+        let version = read_u8(&bytes, &mut pos)?;
+        if version != Block::FORMAT_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let header = BlockHeader::read(&bytes, &mut pos)?;
+        let count = read_u32(&bytes, &mut pos)?;
+        let mut transactions = Vec::<Vec<u8>>::new();
+        for _ in 0..count {
+            transactions.push(read_bytes(&bytes, &mut pos, MAX_TRANSACTION_LENGTH)?);
+        }
+        finish(&bytes, pos)?;
+
+        Ok(Block {
+            header,
+            transactions,
+        })
     }
 }
 
+// What it takes to disconnect a block of the active chain
+#[derive(Debug, Clone)]
+struct BlockUndo {
+    state: StateUndo,
+    // Transactions the block took out of the pool
+    removed_from_pool: Vec<MempoolEntry>,
+}
+
 #[derive(Debug, Clone)]
 pub struct BlockChain {
-    transaction_pool: Vec<Vec<u8>>,
+    // Transactions waiting to be mined
+    mempool: Mempool,
     chain: Vec<Block>,
     blockchain_address: String,
+    // Where mined blocks are persisted, None keeps the chain in memory only
+    store: Option<BlockStore>,
+    // Every known block including side branches, `chain` is the branch with the most work
+    block_tree: BlockTree,
+    miner: Miner,
+    // Lookups into `chain`, updated whenever a block is connected or disconnected
+    index: BlockIndex,
+    // Balances and unspent outputs as of the tip of `chain`
+    state: ChainState,
+    // Undo data of every block of `chain`, in the same order
+    undo: Vec<BlockUndo>,
+    // Consensus rules of the network this chain belongs to
+    params: ChainParams,
 }
 impl BlockChain {
-    const DIFFICULTY: usize = 4;
-    const MINING_SENDER: &str = "THE BLOCKCHAIN";
-    const MINING_REWARD: u64 = 1;
+    // Limits of the pool of transactions waiting to be mined
+    const MEMPOOL_MAX_TRANSACTIONS: usize = 5_000;
+    const MEMPOOL_MAX_BYTES: usize = 5_000_000;
+    const MEMPOOL_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+    // Fee per 1000 bytes every pool transaction has to pay
+    const MEMPOOL_MIN_FEE_RATE: u64 = 1;
+
+    // A chain of only the genesis block, kept in memory
+    pub fn new(address: String, params: ChainParams) -> Self {
+        let genesis = Block::genesis(&params);
+        // The parameters are compiled in, a mismatch means they were edited inconsistently
+        if hex::encode(genesis.hash()) != params.genesis_hash {
+            panic!(
+                "The {} genesis block hashes to {} instead of {}",
+                params.name,
+                hex::encode(genesis.hash()),
+                params.genesis_hash
+            );
+        }
+        let mut bc = BlockChain::empty(address, params, None);
+        bc.connect_block(genesis);
+        bc
+    }
 
-    pub fn new(address: String) -> Self {
-        let mut bc = BlockChain {
-            transaction_pool: Vec::<Vec<u8>>::new(),
+    fn empty(address: String, params: ChainParams, store: Option<BlockStore>) -> Self {
+        BlockChain {
+            mempool: Mempool::new(
+                BlockChain::MEMPOOL_MAX_TRANSACTIONS,
+                BlockChain::MEMPOOL_MAX_BYTES,
+                BlockChain::MEMPOOL_EXPIRY,
+                BlockChain::MEMPOOL_MIN_FEE_RATE,
+            ),
             chain: Vec::<Block>::new(),
             blockchain_address: address,
+            store,
+            block_tree: BlockTree::new(),
+            miner: Miner::with_available_parallelism(),
+            index: BlockIndex::new(),
+            state: ChainState::new(params.clone()),
+            undo: Vec::<BlockUndo>::new(),
+            params,
+        }
+    }
+
+    // Writes the active chain, genesis block first, as a block stream
+    pub fn export_blocks(&self, writer: impl Write) -> io::Result<()> {
+        let mut writer = BlockWriter::new(writer)?;
+        for block in self.chain.iter() {
+            writer.write_block(block)?;
+        }
+        writer.finish().map(|_| ())
+    }
+
+    // Hands every block of a block stream to `accept_block` and returns what happened to
+    // each of them. Blocks read before a broken one are kept.
+    pub fn import_blocks(&mut self, reader: impl Read) -> io::Result<Vec<BlockAcceptance>> {
+        let mut outcomes = Vec::<BlockAcceptance>::new();
+        for block in BlockReader::new(reader)? {
+            let outcome = self
+                .accept_block(block?)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            outcomes.push(outcome);
+        }
+        Ok(outcomes)
+    }
+
+    // Rebuilds the chain from the block store in `dir`, or starts a new one if it is empty
+    pub fn open(address: String, params: ChainParams, dir: impl AsRef<Path>) -> io::Result<Self> {
+        let store = BlockStore::open(dir)?;
+        let blocks = store.load_blocks()?;
+        if blocks.is_empty() {
+            let mut bc = BlockChain::new(address, params);
+            store.append(&bc.chain[0])?;
+            bc.store = Some(store);
+            return Ok(bc);
+        }
+        // A store started from another genesis block belongs to another chain
+        if hex::encode(blocks[0].hash()) != params.genesis_hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the block store starts from genesis block {}, {} uses {}",
+                    hex::encode(blocks[0].hash()),
+                    params.name,
+                    params.genesis_hash
+                ),
+            ));
+        }
+        // Never trust what was read from disk, connecting takes the blocks as valid
+        let mut bc = BlockChain::empty(address, params, None);
+        if let Err(reason) = bc.validate_blocks(&blocks).into_result() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                reason.to_string(),
+            ));
+        }
+        // The blocks are already stored, only attach the store once they are all in
+        for block in blocks {
+            bc.connect_block(block);
+        }
+        bc.store = Some(store);
+        println!("Loaded {} blocks from the block store", bc.chain.len());
+        Ok(bc)
+    }
+
+    // Stores `block` and connects it. A block the store could not take is not connected.
+    fn push_block(&mut self, block: Block) -> Result<(), BlockChainError> {
+        if let Some(store) = &self.store {
+            store.append(&block)?;
+        }
+        self.connect_block(block);
+        // A block being mined on the old tip could never be connected
+        self.miner.handle().cancel();
+        Ok(())
+    }
+
+    // Makes `block` the new tip of the active chain, in memory only
+    fn connect_block(&mut self, block: Block) {
+        self.block_tree.insert(block.clone());
+        self.index.insert(self.chain.len(), &block);
+        let state = self.state.connect_block(&block);
+        let removed_from_pool = block
+            .transactions
+            .iter()
+            .filter_map(|tx| self.mempool.remove(&Transaction::id_of(tx)))
+            .collect();
+        self.undo.push(BlockUndo {
+            state,
+            removed_from_pool,
+        });
+        self.chain.push(block);
+    }
+
+    // Connects a block again after it was disconnected, keeping what it took out of the pool
+    fn reconnect_block(&mut self, block: Block, undo: BlockUndo) {
+        self.connect_block(block);
+        if let Some(last) = self.undo.last_mut() {
+            // Whatever it takes out of the pool this time is kept as well
+            let removed = std::mem::replace(&mut last.removed_from_pool, undo.removed_from_pool);
+            for entry in removed {
+                if !last
+                    .removed_from_pool
+                    .iter()
+                    .any(|kept| kept.txid == entry.txid)
+                {
+                    last.removed_from_pool.push(entry);
+                }
+            }
+        }
+    }
+
+    // Puts back the pool entries that disconnected blocks took out when they were connected,
+    // with their arrival time
+    fn restore_mempool(&mut self, disconnected: &[(Block, BlockUndo)]) {
+        for (_, undo) in disconnected.iter() {
+            for entry in undo.removed_from_pool.iter() {
+                // No room for it, the pool was full before the block took it out too
+                let _ = self.insert_into_mempool(entry.transaction.clone(), entry.added);
+            }
+        }
+    }
+
+    // Removes every block above `height` from the active chain and returns them in
+    // chain order with their undo data. They stay in the block tree.
+    fn disconnect_to(&mut self, height: usize) -> Vec<(Block, BlockUndo)> {
+        let mut disconnected = Vec::<(Block, BlockUndo)>::new();
+        while self.chain.len() > height + 1 {
+            let block = self.chain.pop().unwrap();
+            let undo = self.undo.pop().unwrap();
+            self.index.remove(self.chain.len(), &block);
+            self.state.disconnect_block(&undo.state);
+            disconnected.push((block, undo));
+        }
+        disconnected.reverse();
+        disconnected
+    }
+
+    // Puts the transactions of disconnected blocks back into the pool, except the ones the
+    // active chain confirms again. The rewards of the blocks are gone with them.
+    fn return_to_mempool(&mut self, disconnected: &[(Block, BlockUndo)]) {
+        for (block, undo) in disconnected.iter() {
+            for tx in block.transactions.iter() {
+                if BlockTransaction::decode(tx).is_coinbase()
+                    || !self
+                        .index
+                        .heights_of_transaction(&Transaction::id_of(tx))
+                        .is_empty()
+                {
+                    continue;
+                }
+                // A transaction that was waiting in the pool keeps its arrival time
+                let added = undo
+                    .removed_from_pool
+                    .iter()
+                    .find(|entry| entry.transaction == *tx)
+                    .map(|entry| entry.added)
+                    .unwrap_or_else(Instant::now);
+                // Already waiting in the pool or no room for it, either way nothing to do
+                let _ = self.insert_into_mempool(tx.clone(), added);
+            }
+        }
+    }
+
+    // Drops the pool entries the active chain no longer allows, such as a spend of an
+    // output that went away with a disconnected block or a nonce the new blocks used.
+    // Each sender's transactions are checked in nonce order, everything else in the
+    // order it arrived.
+    fn revalidate_mempool(&mut self) {
+        let mut entries: Vec<(u64, &MempoolEntry)> = self
+            .mempool
+            .entries()
+            .into_iter()
+            .map(|entry| match BlockTransaction::decode(&entry.transaction) {
+                BlockTransaction::Account(tx) => (tx.nonce, entry),
+                BlockTransaction::Utxo(_) => (0, entry),
+            })
+            .collect();
+        entries.sort_by_key(|(nonce, _)| *nonce);
+        let mut overlay = self.state.overlay();
+        let mut invalid = Vec::<Vec<u8>>::new();
+        for (_, entry) in entries {
+            if let Err(reason) = overlay.apply(&BlockTransaction::decode(&entry.transaction)) {
+                println!(
+                    "Dropping transaction {} from the pool: {}",
+                    hex::encode(&entry.txid),
+                    reason
+                );
+                invalid.push(entry.txid.clone());
+            }
+        }
+        for txid in invalid.iter() {
+            self.mempool.remove(txid);
+        }
+    }
+
+    // Disconnects every block above `height` and puts their transactions back into the
+    // pool. The blocks are forgotten so they can be accepted again. Returns how many
+    // blocks were disconnected.
+    pub fn rollback_to(&mut self, height: usize) -> Result<usize, BlockChainError> {
+        if height + 1 >= self.chain.len() {
+            return Ok(0);
+        }
+        // The store goes first, if it fails the chain is left as it was
+        if let Some(store) = &self.store {
+            store.truncate(height + 1)?;
+        }
+        let disconnected = self.disconnect_to(height);
+        for (block, _) in disconnected.iter() {
+            self.block_tree.remove(&block.hash());
+        }
+        self.return_to_mempool(&disconnected);
+        self.revalidate_mempool();
+        println!(
+            "Rolled back to height {}: {} block(s) disconnected",
+            height,
+            disconnected.len()
+        );
+        Ok(disconnected.len())
+    }
+
+    // Takes a block mined elsewhere. It either extends the active chain, is kept on a
+    // side branch, or triggers a reorganization if its branch now has the most work.
+    pub fn accept_block(&mut self, block: Block) -> Result<BlockAcceptance, BlockChainError> {
+        let hash = block.hash();
+        if self.block_tree.contains(&hash) {
+            return Ok(BlockAcceptance::AlreadyKnown);
+        }
+        if let Err(reason) = self.check_proof(&block, false) {
+            return Ok(BlockAcceptance::Rejected(reason));
+        }
+        // 1. The common case, the block builds on our tip
+        if block.header.previous_hash == self.last_block().hash() {
+            let result = self
+                .check_block(&self.chain, &block, false)
+                .and_then(|_| self.state.check_block(&block, false));
+            if let Err(reason) = result {
+                return Ok(BlockAcceptance::Rejected(reason));
+            }
+            self.push_block(block)?;
+            return Ok(BlockAcceptance::Extended);
+        }
+        // 2. Otherwise it belongs to a side branch, which needs a known parent. Only the
+        // checks that need no state have run on its body, so a body that fails once its
+        // branch is connected is forgotten again and the honest one can still come in.
+        let chain_work = match self.block_tree.insert(block) {
+            Some(node) => node.chain_work,
+            None => {
+                return Ok(BlockAcceptance::Rejected(
+                    ChainValidationError::UnknownParent,
+                ))
+            }
         };
-        let b = Block::new(0, vec![0 as u8; 32]);
-        bc.chain.push(b);
-        bc.mining();
-        bc
+        // 3. Only a branch with strictly more work replaces the active chain
+        if chain_work <= self.chain_work() {
+            return Ok(BlockAcceptance::SideBranch);
+        }
+        self.reorganize(&hash)
     }
 
-    pub fn create_block(&mut self, nonce: i32, previous_hash: Vec<u8>) {
-        let mut b = Block::new(nonce, previous_hash);
-        for tx in self.transaction_pool.iter() {
-            b.transactions.push(tx.clone());
+    // Cumulative proof of work of the active chain
+    pub fn chain_work(&self) -> u128 {
+        self.block_tree
+            .get(&self.last_block().hash())
+            .map(|node| node.chain_work)
+            .unwrap_or(0)
+    }
+
+    fn reorganize(&mut self, tip: &[u8]) -> Result<BlockAcceptance, BlockChainError> {
+        // 1. Walk back from the new tip to the block where it forks off the active chain
+        let chain = &self.chain;
+        let branch = self.block_tree.branch(tip, |node| {
+            chain
+                .get(node.height)
+                .is_some_and(|block| block.hash() == node.block.hash())
+        });
+        let (fork_height, branch) = match branch {
+            Some(nodes) if !nodes.is_empty() => (
+                nodes[0].height - 1,
+                nodes
+                    .into_iter()
+                    .map(|node| node.block.clone())
+                    .collect::<Vec<Block>>(),
+            ),
+            _ => {
+                return Ok(BlockAcceptance::Rejected(
+                    ChainValidationError::UnknownParent,
+                ))
+            }
+        };
+        // 2. Roll the active chain and its balances back to the fork point
+        let disconnected = self.disconnect_to(fork_height);
+        // 3. Connect the new branch one block at a time
+        for (i, block) in branch.iter().enumerate() {
+            let result = self
+                .check_block(&self.chain, block, false)
+                .and_then(|_| self.state.check_block(block, false));
+            if let Err(reason) = result {
+                // Forget the invalid block and whatever this branch built on top of it,
+                // then put the old branch back
+                for invalid in branch[i..].iter() {
+                    self.block_tree.remove(&invalid.hash());
+                }
+                // The part of the branch already connected gives back what it took from
+                // the pool, the old branch then takes out whatever it confirms again
+                let connected = self.disconnect_to(fork_height);
+                self.restore_mempool(&connected);
+                for (block, undo) in disconnected {
+                    self.reconnect_block(block, undo);
+                }
+                self.revalidate_mempool();
+                return Ok(BlockAcceptance::Rejected(reason));
+            }
+            self.connect_block(block.clone());
         }
-        self.transaction_pool.clear();
-        let now = Instant::now();
-        let proof_hash = BlockChain::do_proof_of_work(&mut b);
-        let elapsted_time = now.elapsed();
+        // 4. Only now rewrite the block store. If that fails the old branch comes back, on
+        // disk as far as it lets us.
+        if let Err(error) = self.store_from(fork_height + 1) {
+            let connected = self.disconnect_to(fork_height);
+            self.restore_mempool(&connected);
+            for (block, undo) in disconnected {
+                self.reconnect_block(block, undo);
+            }
+            self.revalidate_mempool();
+            if let Err(e) = self.store_from(fork_height + 1) {
+                println!(
+                    "Error writing the old branch back to the block store: {}",
+                    e
+                );
+            }
+            return Err(error);
+        }
+        // 5. Transactions only confirmed on the old branch go back to the pool
+        self.return_to_mempool(&disconnected);
+        self.revalidate_mempool();
+        self.miner.handle().cancel();
         println!(
-            "Compute time: {:?}\nProof Hash for the current block is:{:?}",
-            elapsted_time, proof_hash
+            "Reorganized at height {}: {} block(s) disconnected, {} connected",
+            fork_height + 1,
+            disconnected.len(),
+            branch.len()
         );
-        self.chain.push(b);
+        Ok(BlockAcceptance::Reorganized {
+            disconnected: disconnected.len(),
+            connected: branch.len(),
+        })
+    }
+
+    // Replaces whatever the block store holds from `height` on with the active chain
+    fn store_from(&self, height: usize) -> Result<(), BlockChainError> {
+        if let Some(store) = &self.store {
+            store.truncate(height)?;
+            for block in self.chain[height..].iter() {
+                store.append(block)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Mines a block of `transactions` on top of the tip. If they are not valid there or
+    // mining is cancelled, the chain and the pool are left as they were.
+    pub fn create_block(
+        &mut self,
+        nonce: i32,
+        previous_hash: Vec<u8>,
+        transactions: Vec<Vec<u8>>,
+    ) -> Result<(), BlockChainError> {
+        let invalid = |reason| BlockChainError::InvalidBlock {
+            height: self.chain.len(),
+            reason,
+        };
+        // Only the tip can be built on here, a side branch has to go through accept_block
+        let tip = self.last_block().hash();
+        if previous_hash != tip {
+            return Err(invalid(ChainValidationError::PreviousHashMismatch {
+                expected: tip,
+                found: previous_hash,
+            }));
+        }
+        let mut b = self.assemble_block(nonce, previous_hash, transactions)?;
+        BlockChain::mine_block(&self.miner, &mut b)?;
+        // The same checks as a block mined elsewhere, the header included
+        self.check_block(&self.chain, &b, false).map_err(invalid)?;
+        // Connecting the block takes its transactions out of the pool
+        self.push_block(b)
+    }
+
+    // The block of `transactions` to mine next, checked against the chain state. From
+    // now on a new tip cancels mining it.
+    fn assemble_block(
+        &self,
+        nonce: i32,
+        previous_hash: Vec<u8>,
+        transactions: Vec<Vec<u8>>,
+    ) -> Result<Block, BlockChainError> {
+        let mut b = Block::new(nonce, previous_hash, self.next_bits());
+        // Even with the local clock behind, the block has to come after its parents
+        let earliest = self.median_time_past(&self.chain) + 1;
+        b.header.time_stamp = b.header.time_stamp.max(earliest);
+        b.transactions = transactions;
+        b.header.merkle_root = merkle::merkle_root(&b.transactions);
+        // No point mining a block that would be turned down
+        self.state
+            .check_block(&b, false)
+            .map_err(|reason| BlockChainError::InvalidBlock {
+                height: self.chain.len(),
+                reason,
+            })?;
+        self.miner.begin_block();
+        Ok(b)
+    }
+
+    // Searches for the proof of work of `b`. It only needs the miner, so it can run
+    // without holding on to the chain.
+    pub fn mine_block(miner: &Miner, b: &mut Block) -> Result<(), BlockChainError> {
+        loop {
+            let (outcome, stats) = miner.mine(&b.header);
+            println!(
+                "Compute time: {:?}\nHashrate: {:.0} H/s on {} thread(s)",
+                stats.elapsed,
+                stats.hashrate(),
+                miner.threads()
+            );
+            match outcome {
+                MiningOutcome::Found { nonce, hash } => {
+                    b.header.nonce = nonce;
+                    println!(
+                        "Proof Hash for the current block is:{:?}",
+                        hex::encode(hash)
+                    );
+                    break;
+                }
+                MiningOutcome::Cancelled => return Err(BlockChainError::MiningCancelled),
+                // A fresh timestamp gives a whole new nonce space to search
+                MiningOutcome::Exhausted => {
+                    b.header.time_stamp = BlockChain::now().max(b.header.time_stamp + 1);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_mining_threads(&mut self, threads: usize) {
+        self.miner.set_threads(threads);
+    }
+
+    pub fn miner(&self) -> &Miner {
+        &self.miner
+    }
+
+    // Handle another thread can use to cancel the block currently being mined
+    pub fn miner_handle(&self) -> MinerHandle {
+        self.miner.handle()
     }
 
     pub fn print(&self) {
@@ -148,141 +844,578 @@ impl BlockChain {
         &self.chain[0]
     }
 
-    pub fn search_block(&self, search: BlockSearch) -> BlockSearchResult {
-        for (idx, block) in self.chain.iter().enumerate() {
-            match search {
-                BlockSearch::SearchByIndex(index) => {
-                    if idx == index {
-                        return BlockSearchResult::Success(block);
-                    } else {
-                        return BlockSearchResult::FailOfIndex(index);
-                    }
+    // Height of the tip, the genesis block is at 0
+    pub fn height(&self) -> usize {
+        self.chain.len() - 1
+    }
+
+    pub fn search_block(&self, search: BlockSearch) -> BlockSearchResult<'_> {
+        if self.chain.is_empty() {
+            return BlockSearchResult::FailOfEmptyBlocks;
+        }
+        match search {
+            BlockSearch::SearchByIndex(index) => match self.chain.get(index) {
+                Some(block) => BlockSearchResult::Success(block),
+                None => BlockSearchResult::FailOfIndex(index),
+            },
+
+            BlockSearch::SearchByPreviousHash(hash) => {
+                match self.index.height_of_previous_hash(&hash) {
+                    Some(height) => BlockSearchResult::Success(&self.chain[height]),
+                    None => BlockSearchResult::FailOfPreviousHash(hash),
                 }
+            }
 
-                BlockSearch::SearchByPreviousHash(ref hash) => {
-                    if block.previous_hash == *hash {
-                        return BlockSearchResult::Success(block);
-                    } else {
-                        return BlockSearchResult::FailOfPreviousHash(hash.to_vec());
-                    }
+            BlockSearch::SearchByBlockHash(hash) => match self.index.height_of_hash(&hash) {
+                Some(height) => BlockSearchResult::Success(&self.chain[height]),
+                None => BlockSearchResult::FailOfBlockHash(hash),
+            },
+
+            BlockSearch::SearchByNonce(nonce) => {
+                match self.blocks_at(self.index.heights_of_nonce(nonce)) {
+                    Some(blocks) => BlockSearchResult::Matches(blocks),
+                    None => BlockSearchResult::FailOfNonce(nonce),
                 }
+            }
 
-                BlockSearch::SearchByBlockHash(ref hash) => {
-                    if block.hash() == *hash {
-                        return BlockSearchResult::Success(block);
-                    } else {
-                        return BlockSearchResult::FailOfBlockHash(hash.to_vec());
-                    }
+            BlockSearch::SearchByTimestamp(timestamp) => {
+                let heights = self.index.heights_in_time_range(timestamp, timestamp);
+                match self.blocks_at(&heights) {
+                    Some(blocks) => BlockSearchResult::Matches(blocks),
+                    None => BlockSearchResult::FailOfTimestamp(timestamp),
                 }
+            }
 
-                BlockSearch::SearchByNonce(nonce) => {
-                    if block.nonce == nonce {
-                        return BlockSearchResult::Success(block);
-                    } else {
-                        return BlockSearchResult::FailOfNonce(nonce);
-                    }
+            BlockSearch::SearchByTimestampRange(from, to) => {
+                let heights = self.index.heights_in_time_range(from, to);
+                match self.blocks_at(&heights) {
+                    Some(blocks) => BlockSearchResult::Matches(blocks),
+                    None => BlockSearchResult::FailOfTimestampRange(from, to),
                 }
+            }
 
-                BlockSearch::SearchByTimestamp(timestamp) => {
-                    if block.time_stamp == timestamp {
-                        return BlockSearchResult::Success(block);
-                    } else {
-                        return BlockSearchResult::FailOfTimestamp(timestamp);
-                    }
+            BlockSearch::SearchByTransaction(transaction) => {
+                let txid = Transaction::id_of(&transaction);
+                match self.blocks_at(self.index.heights_of_transaction(&txid)) {
+                    Some(blocks) => BlockSearchResult::Matches(blocks),
+                    None => BlockSearchResult::FailOfTransaction(transaction),
                 }
+            }
 
-                BlockSearch::SearchByTransaction(ref transaction) => {
-                    for tx in block.transactions.iter() {
-                        if transaction == tx {
-                            return BlockSearchResult::Success(block);
-                        } else {
-                            return BlockSearchResult::FailOfTransaction(transaction.to_vec());
-                        }
-                    }
+            BlockSearch::SearchByTransactionId(txid) => {
+                match self.blocks_at(self.index.heights_of_transaction(&txid)) {
+                    Some(blocks) => BlockSearchResult::Matches(blocks),
+                    None => BlockSearchResult::FailOfTransactionId(txid),
                 }
             }
         }
+    }
 
-        return BlockSearchResult::FailOfEmptyBlocks;
+    // Looks a confirmed transaction up by ID. Should the same transaction be in several
+    // blocks, the earliest one is returned.
+    pub fn get_transaction(&self, txid: &[u8]) -> Option<TransactionLookup> {
+        let block_height = *self.index.heights_of_transaction(txid).iter().min()?;
+        let block = &self.chain[block_height];
+        let tx = block
+            .transactions
+            .iter()
+            .find(|tx| Transaction::id_of(tx) == txid)?;
+        Some(TransactionLookup {
+            transaction: BlockTransaction::decode(tx),
+            block_height,
+            block_hash: block.hash(),
+            confirmations: self.chain.len() - block_height,
+        })
     }
 
-    pub fn add_transaction(&mut self, tx: &WalletTransaction) -> bool {
+    // The blocks at `heights` in chain order, None if there are none
+    fn blocks_at(&self, heights: &[usize]) -> Option<Vec<&Block>> {
+        if heights.is_empty() {
+            return None;
+        }
+        let mut heights = heights.to_vec();
+        heights.sort_unstable();
+        heights.dedup();
+        Some(heights.iter().map(|h| &self.chain[*h]).collect())
+    }
+
+    pub fn add_transaction<'a>(
+        &mut self,
+        tx: impl Into<PendingTransaction<'a>>,
+    ) -> Result<(), BlockChainError> {
+        // Expired transactions would still hold on to their outputs, nonces and room
+        self.expire_mempool();
+        match tx.into() {
+            PendingTransaction::Account(tx) => self.add_account_transaction(tx),
+            PendingTransaction::Utxo(tx) => self.add_utxo_transaction(tx),
+        }
+    }
+
+    fn add_account_transaction(&mut self, tx: &WalletTransaction) -> Result<(), BlockChainError> {
         // Making sure we are not sending to ourself
         if tx.sender == self.blockchain_address {
-            println!("The miner cannot send money to themselves");
-            return false;
-        }
-        // We should also avoid verify the transaction that is a reward from the blockchain
-        if tx.sender != BlockChain::MINING_SENDER && !Wallet::verify_transaction(tx) {
-            println!("Invalid transation");
-            return false;
-        }
-        // Making sure the sender has enough balance to send a particular amount of money
-        // The sender should also not be the blockchain
-        if tx.sender != BlockChain::MINING_SENDER
-            && self.calculate_total_amount(tx.sender.clone()) < tx.amount as i64
-        {
-            println!("The sender doesn't have enough balance to make that transation");
-            return false;
-        }
-
-        let transaction = Transaction::new(
-            tx.sender.as_bytes().to_vec(),
-            tx.recipient.as_bytes().to_vec(),
-            tx.amount,
-        );
-        for tx_in_pool in self.transaction_pool.iter() {
-            if *tx_in_pool == transaction.serialization() {
-                break;
+            return Err(BlockChainError::SelfSend);
+        }
+        // The key has to own the sender's address and sign the transaction
+        if !Wallet::verify_transaction(tx, self.params.address_version) {
+            return Err(BlockChainError::InvalidSignature);
+        }
+        // The signed nonce makes a replayed or reordered transaction stand out
+        let expected = self.next_nonce(&tx.sender);
+        if tx.nonce < expected {
+            return Err(BlockChainError::NonceAlreadyUsed { nonce: tx.nonce });
+        }
+        if tx.nonce > expected {
+            return Err(BlockChainError::NonceTooHigh {
+                expected,
+                found: tx.nonce,
+            });
+        }
+        // Making sure the sender has enough balance to send a particular amount of money,
+        // on top of what they already send in transactions waiting in the pool
+        let required = tx
+            .amount
+            .checked_add(tx.fee)
+            .filter(|required| *required <= i64::MAX as u64)
+            .ok_or(BlockChainError::InvalidTransaction(
+                ChainValidationError::ValueOverflow,
+            ))?;
+        let pending =
+            i64::try_from(self.mempool.pending_outflow(tx.sender.as_bytes())).unwrap_or(i64::MAX);
+        let available = self
+            .calculate_total_amount(tx.sender.clone())
+            .saturating_sub(pending);
+        if available < required as i64 {
+            return Err(BlockChainError::InsufficientBalance {
+                available,
+                required,
+            });
+        }
+
+        let transaction = tx
+            .to_account_transaction()
+            .ok_or(BlockChainError::InvalidSignature)?;
+        // Addresses come from the client and may be longer than any decoder accepts
+        let serialized = transaction.serialization();
+        if let Err(reason) = BlockTransaction::try_decode(&serialized) {
+            return Err(BlockChainError::InvalidTransaction(
+                ChainValidationError::MalformedTransaction { index: 0, reason },
+            ));
+        }
+        self.add_to_mempool(serialized)
+    }
+
+    // Nonce the next account transaction from `address` has to be signed with, counting
+    // the transactions it has waiting in the pool
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        let confirmed = self.state.next_nonce(address.as_bytes());
+        self.mempool.next_nonce(address.as_bytes(), confirmed)
+    }
+
+    fn add_utxo_transaction(&mut self, tx: &UtxoTransaction) -> Result<(), BlockChainError> {
+        // 1. No output may be spent by two transactions waiting in the pool
+        for input in tx.inputs.iter() {
+            let outpoint = &input.previous_output;
+            if self.mempool.spender_of(outpoint).is_some() {
+                return Err(BlockChainError::ConflictingSpend {
+                    txid: outpoint.txid.clone(),
+                    index: outpoint.index,
+                });
             }
         }
-        self.transaction_pool.push(transaction.serialization());
-        true
+        // 2. Signatures, ownership and amounts are checked against the confirmed outputs,
+        // and the balances left once the pool is mined
+        let serialized = tx.serialization();
+        self.state
+            .check_transactions(
+                std::slice::from_ref(&serialized),
+                self.mempool.pending_outflows(),
+            )
+            .map_err(BlockChainError::InvalidTransaction)?;
+        self.add_to_mempool(serialized)
     }
 
-    fn do_proof_of_work(block: &mut Block) -> String {
-        loop {
-            let hash = block.hash();
-            let hash_str = hex::encode(&hash);
-            if hash_str[0..BlockChain::DIFFICULTY] == "0".repeat(BlockChain::DIFFICULTY) {
-                return hash_str;
+    fn insert_into_mempool(
+        &mut self,
+        transaction: Vec<u8>,
+        added: Instant,
+    ) -> Result<Vec<Vec<u8>>, MempoolRejection> {
+        let decoded = BlockTransaction::decode(&transaction);
+        let fee = self.state.fee_of(&decoded);
+        let outflows = self.state.outflows_of(&decoded);
+        self.mempool.insert_at(transaction, fee, outflows, added)
+    }
+
+    fn add_to_mempool(&mut self, transaction: Vec<u8>) -> Result<(), BlockChainError> {
+        let evicted = self.insert_into_mempool(transaction, Instant::now())?;
+        if !evicted.is_empty() {
+            println!("Evicted {} transaction(s) from the pool", evicted.len());
+        }
+        Ok(())
+    }
+
+    pub fn mempool_stats(&mut self) -> MempoolStats {
+        self.expire_mempool();
+        self.mempool.stats()
+    }
+
+    fn expire_mempool(&mut self) {
+        let expired = self.mempool.expire();
+        if expired > 0 {
+            println!("Dropped {} expired transaction(s) from the pool", expired);
+        }
+    }
+
+    // Unspent outputs of the active chain paying to `address`. They are part of its
+    // balance and never add up to more, see `ChainState`.
+    pub fn unspent_outputs(&self, address: &str) -> Vec<(OutPoint, TxOutput)> {
+        self.state
+            .utxos()
+            .outputs_of(address.as_bytes())
+            .into_iter()
+            .map(|(outpoint, output)| (outpoint.clone(), output.clone()))
+            .collect()
+    }
+
+    fn is_valid_proof(hash: &[u8], bits: u32) -> bool {
+        match Target::from_compact(bits) {
+            Some(target) => target.is_met_by(hash),
+            None => false,
+        }
+    }
+
+    // Compact target the next block appended to the chain has to be mined at
+    pub fn next_bits(&self) -> u32 {
+        self.bits_at(self.chain.len())
+    }
+
+    // Compact target required for the block at `height` of the active chain
+    pub fn bits_at(&self, height: usize) -> u32 {
+        self.required_bits(&self.chain, height)
+    }
+
+    // Compact target for the block at `height`, derived only from the blocks before it
+    fn required_bits(&self, blocks: &[Block], height: usize) -> u32 {
+        let params = &self.params;
+        if height == 0 {
+            return params.initial_bits;
+        }
+        let previous = blocks[height - 1].header.bits;
+        if !height.is_multiple_of(params.retarget_interval) {
+            return previous;
+        }
+        // 1. How long did the last interval take to mine compared to what we aimed for
+        let first = &blocks[height - params.retarget_interval];
+        let last = &blocks[height - 1];
+        let expected = params.target_block_time * (params.retarget_interval as u128 - 1);
+        // 2. Clamp the measured time so a single retarget cannot swing too far
+        let actual = last
+            .header
+            .time_stamp
+            .saturating_sub(first.header.time_stamp)
+            .clamp(
+                expected / params.max_adjustment_factor,
+                expected * params.max_adjustment_factor,
+            );
+        // 3. Scale the target by the same ratio, a bigger target is easier to meet
+        let pow_limit = Target::from_compact(self.params.pow_limit_bits).unwrap();
+        let target = Target::from_compact(previous)
+            .unwrap_or(pow_limit)
+            .mul_div(actual as u64, expected as u64);
+        target.min(pow_limit).to_compact()
+    }
+
+    // Pool transactions for the next block, highest fee per byte first, leaving
+    // `reserved` bytes free and skipping any that would overdraw a balance. Returns them
+    // with the sum of their fees.
+    fn select_transactions(&self, reserved: usize) -> (Vec<Vec<u8>>, u64) {
+        // 1. Account transactions of a sender can only go in nonce order, so only the
+        // lowest of each sender is a candidate at first. UTXO transactions only spend
+        // confirmed outputs and are all candidates.
+        let mut queues = HashMap::<Vec<u8>, Vec<(u64, &MempoolEntry)>>::new();
+        let mut candidates = BinaryHeap::<Candidate>::new();
+        for entry in self.mempool.entries() {
+            match BlockTransaction::decode(&entry.transaction) {
+                BlockTransaction::Account(tx) => queues
+                    .entry(tx.sender_address)
+                    .or_default()
+                    .push((tx.nonce, entry)),
+                BlockTransaction::Utxo(_) => candidates.push(Candidate(entry, None)),
+            }
+        }
+        for (sender, queue) in queues.iter_mut() {
+            // Highest nonce first, so the next one is popped off the end
+            queue.sort_by_key(|(nonce, _)| Reverse(*nonce));
+            if let Some((_, entry)) = queue.pop() {
+                candidates.push(Candidate(entry, Some(sender.clone())));
+            }
+        }
+        // 2. Take the best paying candidate each time, every transaction is tried once
+        let mut selected = Vec::<Vec<u8>>::new();
+        let mut size = reserved;
+        // Balances as they will be once the selected transactions are mined
+        let mut overlay = self.state.overlay();
+        while let Some(Candidate(entry, sender)) = candidates.pop() {
+            if size + entry.size() > self.params.max_block_bytes {
+                continue;
+            }
+            if let Err(reason) = overlay.apply(&BlockTransaction::decode(&entry.transaction)) {
+                println!(
+                    "Leaving transaction {} out of the block: {}",
+                    hex::encode(&entry.txid),
+                    reason
+                );
+                continue;
+            }
+            size += entry.size();
+            selected.push(entry.transaction.clone());
+            // 3. The sender's next nonce can only follow a transaction that went in
+            if let Some(sender) = sender {
+                if let Some((_, next)) = queues.get_mut(&sender).and_then(|queue| queue.pop()) {
+                    candidates.push(Candidate(next, Some(sender)));
+                }
             }
-            *block += 1;
         }
+        (selected, overlay.fees())
     }
 
-    pub fn mining(&mut self) -> bool {
-        let tx = WalletTransaction {
-            sender: BlockChain::MINING_SENDER.into(),
-            recipient: self.blockchain_address.clone().into(),
-            amount: 20,
-            public_key: String::new(),
-            signature: String::new(),
-        };
-        self.add_transaction(&tx);
-        self.create_block(0, self.last_block().hash());
-        true
+    pub fn mining(&mut self) -> Result<(), BlockChainError> {
+        let mut b = self.block_template()?;
+        BlockChain::mine_block(&self.miner, &mut b)?;
+        self.push_block(b)
+    }
+
+    // The next block to mine on top of the tip: a coinbase and the best paying
+    // transactions of the pool
+    pub fn block_template(&mut self) -> Result<Block, BlockChainError> {
+        self.expire_mempool();
+        // The reward is a coinbase paying the miner the subsidy and every fee in the
+        // block, it goes first. Its size does not depend on the amount.
+        let height = self.chain.len() as u64;
+        let address = self.blockchain_address.as_bytes().to_vec();
+        let reserved = UtxoTransaction::coinbase(height, vec![TxOutput::new(0, address.clone())])
+            .serialization()
+            .len();
+        let (selected, fees) = self.select_transactions(reserved);
+        let value = self.params.block_subsidy(height).checked_add(fees).ok_or(
+            BlockChainError::InvalidTransaction(ChainValidationError::ValueOverflow),
+        )?;
+        let reward = UtxoTransaction::coinbase(height, vec![TxOutput::new(value, address)]);
+        let mut transactions = vec![reward.serialization()];
+        transactions.extend(selected);
+        self.assemble_block(0, self.last_block().hash(), transactions)
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    // Coins in circulation once the block at `height` is connected, None past the tip
+    pub fn circulating_supply(&self, height: u64) -> Option<u64> {
+        self.state.circulating_supply(height)
     }
 
     pub fn calculate_total_amount(&self, address: String) -> i64 {
-        let mut total_amount: i64 = 0;
-        for i in 0..self.chain.len() {
-            let block = &self[i];
-            for t in block.transactions.iter() {
-                let tx = Transaction::deserialization(t.to_vec());
-                let value = tx.value;
-
-                if <String as Into<Vec<u8>>>::into(address.clone()) == tx.recipient_address {
-                    total_amount += value as i64;
-                }
+        self.state.balance(address.as_bytes())
+    }
 
-                if <String as Into<Vec<u8>>>::into(address.clone()) == tx.sender_address {
-                    total_amount -= value as i64;
-                }
+    // Proof that the serialized transaction `tx` is part of the block at `height`
+    pub fn merkle_proof(&self, height: usize, tx: &[u8]) -> Option<MerkleProof> {
+        let block = self.chain.get(height)?;
+        let index = block.transactions.iter().position(|t| t == tx)?;
+        MerkleProof::new(&block.transactions, index)
+    }
+
+    pub fn verify_merkle_proof(&self, height: usize, proof: &MerkleProof) -> bool {
+        match self.chain.get(height) {
+            Some(block) => proof.verify(block.merkle_root()),
+            None => false,
+        }
+    }
+
+    // Block headers from `from_height` up to the tip, enough for a client to check
+    // the proof of work chain and verify merkle proofs without downloading bodies
+    pub fn headers(&self, from_height: usize) -> Vec<BlockHeader> {
+        self.chain
+            .iter()
+            .skip(from_height)
+            .map(|block| block.header.clone())
+            .collect()
+    }
+
+    // Every block of the active chain was either read from the block store or checked as it
+    // came in, so they are decoded the way the store is read
+    pub fn validate_chain(&self) -> ChainValidationReport {
+        self.validate_blocks(&self.chain)
+    }
+
+    fn validate_blocks(&self, blocks: &[Block]) -> ChainValidationReport {
+        if blocks.is_empty() {
+            return ChainValidationReport::invalid(0, ChainValidationError::EmptyChain);
+        }
+        // Replaying every transaction from the genesis block onwards
+        let mut state = ChainState::new(self.params.clone());
+        for (height, block) in blocks.iter().enumerate() {
+            let result = self
+                .check_block(&blocks[..height], block, true)
+                .and_then(|_| state.check_block(block, true));
+            if let Err(reason) = result {
+                return ChainValidationReport::invalid(height, reason);
             }
+            state.connect_block(block);
         }
 
-        total_amount
+        ChainValidationReport::valid(blocks.len())
+    }
+
+    // Median timestamp of the last `median_time_span` blocks of `blocks`, a new block has
+    // to be stamped later than that
+    fn median_time_past(&self, blocks: &[Block]) -> u128 {
+        let start = blocks.len().saturating_sub(self.params.median_time_span);
+        let mut time_stamps: Vec<u128> = blocks[start..]
+            .iter()
+            .map(|block| block.header.time_stamp)
+            .collect();
+        time_stamps.sort();
+        time_stamps.get(time_stamps.len() / 2).copied().unwrap_or(0)
+    }
+
+    fn now() -> u128 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    }
+
+    // Checks that only depend on the block itself and the local clock. Only a `stored`
+    // block may decode transactions of the legacy layout, and only a new one is held
+    // against the clock.
+    fn check_proof(&self, block: &Block, stored: bool) -> Result<(), ChainValidationError> {
+        if block.header.version != BlockHeader::VERSION {
+            return Err(ChainValidationError::UnsupportedVersion(
+                block.header.version,
+            ));
+        }
+        // 1. The merkle root has to commit to exactly the transactions in the block
+        if block.header.merkle_root != merkle::merkle_root(&block.transactions) {
+            return Err(ChainValidationError::MerkleRootMismatch);
+        }
+        // An odd level pairs its last node with itself, so repeating the last transactions
+        // gives the same root. Such a body is not the one the header was mined for.
+        let mut txids = HashSet::new();
+        for tx in block.transactions.iter() {
+            let txid = Transaction::id_of(tx);
+            if !txids.insert(txid.clone()) {
+                return Err(ChainValidationError::DuplicateTransaction { txid });
+            }
+        }
+        // 2. Every transaction has to decode, the rest of the node takes that for granted
+        let decode = if stored {
+            BlockTransaction::try_decode_stored
+        } else {
+            BlockTransaction::try_decode
+        };
+        for (index, tx) in block.transactions.iter().enumerate() {
+            if let Err(reason) = decode(tx) {
+                return Err(ChainValidationError::MalformedTransaction { index, reason });
+            }
+        }
+        // 3. It may not carry more than a block's worth of transactions
+        let size: usize = block.transactions.iter().map(|tx| tx.len()).sum();
+        if size > self.params.max_block_bytes {
+            return Err(ChainValidationError::BlockTooLarge {
+                size,
+                limit: self.params.max_block_bytes,
+            });
+        }
+        // 4. The target may not be easier than the proof of work limit
+        let pow_limit = Target::from_compact(self.params.pow_limit_bits).unwrap();
+        let within_limit =
+            Target::from_compact(block.header.bits).is_some_and(|target| target <= pow_limit);
+        // 5. And the hash has to be at or below that target
+        let hash = block.hash();
+        if !within_limit || !BlockChain::is_valid_proof(&hash, block.header.bits) {
+            return Err(ChainValidationError::InsufficientProofOfWork { hash });
+        }
+        // 6. A block from the future could only be meant to game the retargeting. Blocks
+        // read back from the store were checked against the clock when they came in, the
+        // clock may have moved back since.
+        let limit = BlockChain::now() + self.params.max_future_drift;
+        if !stored && block.header.time_stamp > limit {
+            return Err(ChainValidationError::TimestampTooFarAhead {
+                limit,
+                found: block.header.time_stamp,
+            });
+        }
+        Ok(())
+    }
+
+    // Checks `block` against `previous`, the chain it is appended to
+    fn check_block(
+        &self,
+        previous: &[Block],
+        block: &Block,
+        stored: bool,
+    ) -> Result<(), ChainValidationError> {
+        // 1. The genesis block is not mined, it has to be the one fixed by the parameters
+        let Some(parent) = previous.last() else {
+            if hex::encode(block.hash()) != self.params.genesis_hash
+                || block.header.merkle_root != merkle::merkle_root(&block.transactions)
+            {
+                return Err(ChainValidationError::InvalidGenesis);
+            }
+            return Ok(());
+        };
+        // 2. Every other block has to be linked to the block right before it
+        let expected = parent.hash();
+        if block.header.previous_hash != expected {
+            return Err(ChainValidationError::PreviousHashMismatch {
+                expected,
+                found: block.header.previous_hash.clone(),
+            });
+        }
+        // 3. It has to be stamped later than the blocks right before it
+        let median = self.median_time_past(previous);
+        if block.header.time_stamp <= median {
+            return Err(ChainValidationError::TimestampTooOld {
+                median,
+                found: block.header.time_stamp,
+            });
+        }
+        // 4. It has to be mined at the target the retargeting rules give for its height
+        let expected_bits = self.required_bits(previous, previous.len());
+        if block.header.bits != expected_bits {
+            return Err(ChainValidationError::UnexpectedDifficulty {
+                expected: expected_bits,
+                found: block.header.bits,
+            });
+        }
+        // 5. And be well formed with a valid proof of work
+        self.check_proof(block, stored)
+    }
+}
+
+// A pool entry waiting to be picked for a block, with the sender whose nonce queue it
+// heads if it is an account transaction. The best paying one is the greatest.
+struct Candidate<'a>(&'a MempoolEntry, Option<Vec<u8>>);
+
+impl PartialEq for Candidate<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate<'_> {}
+
+impl PartialOrd for Candidate<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp_priority(other.0)
     }
 }
 
@@ -291,12 +1424,873 @@ impl Index<usize> for BlockChain {
     fn index(&self, index: usize) -> &Self::Output {
         let res = self.chain.get(index);
         match res {
-            Some(block) => {
-                return block;
-            }
+            Some(block) => block,
             None => {
                 panic!("Index out of range");
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::utxo::TxInput;
+
+    fn regtest_chain() -> BlockChain {
+        let params = ChainParams::regtest();
+        let miner = Wallet::new(&params);
+        let mut bc = BlockChain::new(miner.get_address(), params);
+        bc.set_mining_threads(1);
+        bc
+    }
+
+    // The coinbase of the next block, paying its whole subsidy to `address`
+    fn coinbase(bc: &BlockChain, address: &str) -> Vec<u8> {
+        let height = bc.chain.len() as u64;
+        let subsidy = bc.params.block_subsidy(height);
+        UtxoTransaction::coinbase(
+            height,
+            vec![TxOutput::new(subsidy, address.as_bytes().to_vec())],
+        )
+        .serialization()
+    }
+
+    // Mines the next block paying its reward to `wallet`
+    fn fund(bc: &mut BlockChain, wallet: &Wallet) {
+        let transactions = vec![coinbase(bc, &wallet.get_address())];
+        let tip = bc.last_block().hash();
+        bc.create_block(0, tip, transactions).unwrap();
+    }
+
+    // Mines a block on `previous` without connecting it
+    fn mine_on(bc: &BlockChain, previous: Vec<u8>, transactions: Vec<Vec<u8>>) -> Block {
+        let mut block = bc.assemble_block(0, previous, transactions).unwrap();
+        BlockChain::mine_block(&bc.miner, &mut block).unwrap();
+        block
+    }
+
+    fn send(wallet: &Wallet, to: &Wallet, amount: u64, fee: u64, nonce: u64) -> Vec<u8> {
+        wallet
+            .sign_transaction(&to.get_address(), amount, fee, nonce)
+            .to_account_transaction()
+            .unwrap()
+            .serialization()
+    }
+
+    // A block of three transactions and the same block with the last one repeated, which
+    // has the same merkle root and so the same hash
+    fn honest_and_mutated(bc: &BlockChain, alice: &Wallet, bob: &Wallet) -> (Block, Block) {
+        let transactions = vec![
+            coinbase(bc, &bob.get_address()),
+            send(alice, bob, 3, 1, 0),
+            send(alice, bob, 4, 1, 1),
+        ];
+        let honest = mine_on(bc, bc.last_block().hash(), transactions);
+        let mut mutated = honest.clone();
+        mutated.transactions.push(honest.transactions[2].clone());
+        assert_eq!(mutated.hash(), honest.hash());
+        (honest, mutated)
+    }
+
+    #[test]
+    fn repeated_transactions_do_not_shadow_the_honest_block() {
+        let mut bc = regtest_chain();
+        let (alice, bob) = (Wallet::new(bc.params()), Wallet::new(bc.params()));
+        fund(&mut bc, &alice);
+        let (honest, mutated) = honest_and_mutated(&bc, &alice, &bob);
+
+        let duplicate = Transaction::id_of(&honest.transactions[2]);
+        assert_eq!(
+            bc.accept_block(mutated).unwrap(),
+            BlockAcceptance::Rejected(ChainValidationError::DuplicateTransaction {
+                txid: duplicate
+            })
+        );
+        assert_eq!(bc.accept_block(honest).unwrap(), BlockAcceptance::Extended);
+        assert_eq!(bc.calculate_total_amount(bob.get_address()), 27);
+    }
+
+    #[test]
+    fn repeated_transactions_do_not_shadow_an_honest_side_branch() {
+        let mut bc = regtest_chain();
+        let (alice, bob) = (Wallet::new(bc.params()), Wallet::new(bc.params()));
+        fund(&mut bc, &alice);
+        let (honest, mutated) = honest_and_mutated(&bc, &alice, &bob);
+        // Another block at the same height turns both bodies into a side branch
+        let carol = Wallet::new(bc.params());
+        fund(&mut bc, &carol);
+
+        assert!(matches!(
+            bc.accept_block(mutated).unwrap(),
+            BlockAcceptance::Rejected(ChainValidationError::DuplicateTransaction { .. })
+        ));
+        let fork = honest.hash();
+        assert_eq!(
+            bc.accept_block(honest).unwrap(),
+            BlockAcceptance::SideBranch
+        );
+        // The honest body is the one connected once its branch takes over
+        let transactions = vec![coinbase(&bc, &carol.get_address())];
+        let next = mine_on(&bc, fork, transactions);
+        assert_eq!(
+            bc.accept_block(next).unwrap(),
+            BlockAcceptance::Reorganized {
+                disconnected: 1,
+                connected: 2
+            }
+        );
+        assert_eq!(bc.calculate_total_amount(bob.get_address()), 27);
+    }
+
+    #[test]
+    fn replayed_and_skipped_nonces_are_rejected() {
+        let mut bc = regtest_chain();
+        let (alice, bob) = (Wallet::new(bc.params()), Wallet::new(bc.params()));
+        fund(&mut bc, &alice);
+        let bob_address = bob.get_address();
+
+        let first = alice.sign_transaction(&bob_address, 5, 1, 0);
+        bc.add_transaction(&first).unwrap();
+        assert_eq!(bc.next_nonce(&alice.get_address()), 1);
+        assert_eq!(
+            bc.add_transaction(&first),
+            Err(BlockChainError::NonceAlreadyUsed { nonce: 0 })
+        );
+        let skipped = alice.sign_transaction(&bob_address, 5, 1, 2);
+        assert_eq!(
+            bc.add_transaction(&skipped),
+            Err(BlockChainError::NonceTooHigh {
+                expected: 1,
+                found: 2
+            })
+        );
+
+        // Once mined, the transaction can neither come back to the pool nor into a block
+        bc.mining().unwrap();
+        assert_eq!(bc.calculate_total_amount(bob_address.clone()), 5);
+        assert_eq!(
+            bc.add_transaction(&first),
+            Err(BlockChainError::NonceAlreadyUsed { nonce: 0 })
+        );
+        let replayed = first.to_account_transaction().unwrap().serialization();
+        let result = bc.assemble_block(
+            0,
+            bc.last_block().hash(),
+            vec![coinbase(&bc, &bob_address), replayed],
+        );
+        assert!(matches!(
+            result,
+            Err(BlockChainError::InvalidBlock {
+                reason: ChainValidationError::UnexpectedNonce {
+                    expected: 1,
+                    found: 0,
+                    ..
+                },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn out_of_order_nonces_are_rejected_in_a_block() {
+        let mut bc = regtest_chain();
+        let (alice, bob) = (Wallet::new(bc.params()), Wallet::new(bc.params()));
+        fund(&mut bc, &alice);
+        let transactions = vec![
+            coinbase(&bc, &bob.get_address()),
+            send(&alice, &bob, 3, 1, 1),
+            send(&alice, &bob, 4, 1, 0),
+        ];
+        let result = bc.assemble_block(0, bc.last_block().hash(), transactions);
+        assert!(matches!(
+            result,
+            Err(BlockChainError::InvalidBlock {
+                reason: ChainValidationError::UnexpectedNonce {
+                    expected: 0,
+                    found: 1,
+                    ..
+                },
+                ..
+            })
+        ));
+    }
+
+    // Spends every output `wallet` holds to `to`, less a fee of 1
+    fn spend_outputs(bc: &BlockChain, wallet: &Wallet, to: &Wallet) -> UtxoTransaction {
+        let outputs = bc.unspent_outputs(&wallet.get_address());
+        let value: u64 = outputs.iter().map(|(_, output)| output.value).sum();
+        let inputs = outputs
+            .into_iter()
+            .map(|(outpoint, _)| TxInput::new(outpoint))
+            .collect();
+        let address = to.get_address().into_bytes();
+        let mut tx = UtxoTransaction::new(inputs, vec![TxOutput::new(value - 1, address)]);
+        wallet.sign_utxo_transaction(&mut tx);
+        tx
+    }
+
+    fn unspent_value(bc: &BlockChain, wallet: &Wallet) -> u64 {
+        bc.unspent_outputs(&wallet.get_address())
+            .iter()
+            .map(|(_, output)| output.value)
+            .sum()
+    }
+
+    #[test]
+    fn account_send_retires_the_outputs_it_spends() {
+        let mut bc = regtest_chain();
+        let (alice, bob) = (Wallet::new(bc.params()), Wallet::new(bc.params()));
+        fund(&mut bc, &alice);
+        let spend = spend_outputs(&bc, &alice, &bob);
+
+        let send = alice.sign_transaction(&bob.get_address(), 5, 1, 0);
+        bc.add_transaction(&send).unwrap();
+        bc.mining().unwrap();
+        assert_eq!(bc.calculate_total_amount(alice.get_address()), 14);
+        assert!(unspent_value(&bc, &alice) <= 14);
+        // The output the coins came from is gone, it cannot be spent a second time
+        assert!(matches!(
+            bc.add_transaction(&spend),
+            Err(BlockChainError::InvalidTransaction(
+                ChainValidationError::MissingOutput { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn spent_outputs_leave_nothing_to_send() {
+        let mut bc = regtest_chain();
+        let (alice, bob) = (Wallet::new(bc.params()), Wallet::new(bc.params()));
+        fund(&mut bc, &alice);
+
+        bc.add_transaction(&spend_outputs(&bc, &alice, &bob))
+            .unwrap();
+        bc.mining().unwrap();
+        assert_eq!(bc.calculate_total_amount(alice.get_address()), 0);
+        assert_eq!(bc.calculate_total_amount(bob.get_address()), 19);
+        let send = alice.sign_transaction(&bob.get_address(), 5, 1, 0);
+        assert!(matches!(
+            bc.add_transaction(&send),
+            Err(BlockChainError::InsufficientBalance { .. })
+        ));
+    }
+
+    #[test]
+    fn a_block_cannot_spend_the_same_coins_both_ways() {
+        let mut bc = regtest_chain();
+        let (alice, bob) = (Wallet::new(bc.params()), Wallet::new(bc.params()));
+        fund(&mut bc, &alice);
+        let spend = spend_outputs(&bc, &alice, &bob).serialization();
+        let send = send(&alice, &bob, 5, 1, 0);
+
+        for transactions in [[send.clone(), spend.clone()], [spend, send]] {
+            let mut block = vec![coinbase(&bc, &bob.get_address())];
+            block.extend(transactions);
+            assert!(bc.assemble_block(0, bc.last_block().hash(), block).is_err());
+        }
+        // Sending part of the balance keeps whatever outputs still fit in the rest
+        fund(&mut bc, &alice);
+        let send = alice.sign_transaction(&bob.get_address(), 5, 1, 0);
+        bc.add_transaction(&send).unwrap();
+        bc.mining().unwrap();
+        assert_eq!(bc.calculate_total_amount(alice.get_address()), 34);
+        assert_eq!(unspent_value(&bc, &alice), 20);
+    }
+
+    #[test]
+    fn an_output_is_spent_by_one_pool_transaction_at_a_time() {
+        let mut bc = regtest_chain();
+        let (alice, bob, carol) = (
+            Wallet::new(bc.params()),
+            Wallet::new(bc.params()),
+            Wallet::new(bc.params()),
+        );
+        fund(&mut bc, &alice);
+        let to_bob = spend_outputs(&bc, &alice, &bob);
+        let to_carol = spend_outputs(&bc, &alice, &carol);
+        let outpoint = to_bob.inputs[0].previous_output.clone();
+
+        bc.add_transaction(&to_bob).unwrap();
+        assert_eq!(
+            bc.mempool.spender_of(&outpoint),
+            Some(to_bob.id().as_slice())
+        );
+        assert_eq!(
+            bc.add_transaction(&to_carol),
+            Err(BlockChainError::ConflictingSpend {
+                txid: outpoint.txid.clone(),
+                index: outpoint.index
+            })
+        );
+        bc.mining().unwrap();
+        assert_eq!(bc.mempool.spender_of(&outpoint), None);
+        assert_eq!(bc.calculate_total_amount(bob.get_address()), 19);
+    }
+
+    #[test]
+    fn blocks_take_the_best_paying_transactions_in_nonce_order() {
+        let mut bc = regtest_chain();
+        let (alice, bob, carol) = (
+            Wallet::new(bc.params()),
+            Wallet::new(bc.params()),
+            Wallet::new(bc.params()),
+        );
+        fund(&mut bc, &alice);
+        fund(&mut bc, &carol);
+        let first = alice.sign_transaction(&bob.get_address(), 1, 1, 0);
+        let second = alice.sign_transaction(&bob.get_address(), 1, 10, 1);
+        let other = carol.sign_transaction(&bob.get_address(), 1, 5, 0);
+        for tx in [&first, &second, &other] {
+            bc.add_transaction(tx).unwrap();
+        }
+
+        // Alice's second transaction pays the most but has to wait for her first
+        let template = bc.block_template().unwrap();
+        let expected: Vec<Vec<u8>> = [&other, &first, &second]
+            .iter()
+            .map(|tx| tx.to_account_transaction().unwrap().serialization())
+            .collect();
+        assert_eq!(template.transactions[1..], expected[..]);
+    }
+
+    #[test]
+    fn transactions_without_a_fee_are_turned_away() {
+        let mut bc = regtest_chain();
+        let (alice, bob) = (Wallet::new(bc.params()), Wallet::new(bc.params()));
+        fund(&mut bc, &alice);
+        let free = alice.sign_transaction(&bob.get_address(), 5, 0, 0);
+        assert!(matches!(
+            bc.add_transaction(&free),
+            Err(BlockChainError::Mempool(MempoolRejection::FeeTooLow {
+                fee: 0,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn pending_transactions_count_against_the_balance() {
+        let mut bc = regtest_chain();
+        let (alice, bob) = (Wallet::new(bc.params()), Wallet::new(bc.params()));
+        fund(&mut bc, &alice);
+        let bob_address = bob.get_address();
+
+        bc.add_transaction(&alice.sign_transaction(&bob_address, 12, 1, 0))
+            .unwrap();
+        // 20 less the 13 waiting in the pool leaves 7
+        assert_eq!(
+            bc.add_transaction(&alice.sign_transaction(&bob_address, 7, 1, 1)),
+            Err(BlockChainError::InsufficientBalance {
+                available: 7,
+                required: 8
+            })
+        );
+        bc.add_transaction(&alice.sign_transaction(&bob_address, 6, 1, 1))
+            .unwrap();
+        bc.mining().unwrap();
+        assert_eq!(bc.calculate_total_amount(alice.get_address()), 0);
+        assert_eq!(bc.calculate_total_amount(bob_address), 18);
+    }
+
+    #[test]
+    fn blocks_may_not_overdraw_a_balance() {
+        let mut bc = regtest_chain();
+        let (alice, bob) = (Wallet::new(bc.params()), Wallet::new(bc.params()));
+        fund(&mut bc, &alice);
+        let transactions = vec![
+            coinbase(&bc, &bob.get_address()),
+            send(&alice, &bob, 12, 1, 0),
+            send(&alice, &bob, 7, 1, 1),
+        ];
+        let result = bc.assemble_block(0, bc.last_block().hash(), transactions);
+        assert!(matches!(
+            result,
+            Err(BlockChainError::InvalidBlock {
+                reason: ChainValidationError::NegativeBalance { balance: -1, .. },
+                ..
+            })
+        ));
+        // A greedy block built around the pool check is turned down all the same
+        let mut block = Block::new(0, bc.last_block().hash(), bc.next_bits());
+        block.transactions = vec![
+            coinbase(&bc, &bob.get_address()),
+            send(&alice, &bob, 12, 1, 0),
+            send(&alice, &bob, 7, 1, 1),
+        ];
+        block.header.merkle_root = merkle::merkle_root(&block.transactions);
+        bc.miner.begin_block();
+        BlockChain::mine_block(&bc.miner, &mut block).unwrap();
+        assert!(matches!(
+            bc.accept_block(block).unwrap(),
+            BlockAcceptance::Rejected(ChainValidationError::NegativeBalance { .. })
+        ));
+    }
+
+    #[test]
+    fn rollback_restores_the_state_and_the_pool() {
+        let mut bc = regtest_chain();
+        let (alice, bob) = (Wallet::new(bc.params()), Wallet::new(bc.params()));
+        fund(&mut bc, &alice);
+        fund(&mut bc, &alice);
+        let (alice_address, bob_address) = (alice.get_address(), bob.get_address());
+        let outputs = bc.unspent_outputs(&alice_address);
+
+        let tx = alice.sign_transaction(&bob_address, 25, 1, 0);
+        let txid = tx.to_account_transaction().unwrap().id();
+        bc.add_transaction(&tx).unwrap();
+        bc.mining().unwrap();
+        assert_eq!(bc.calculate_total_amount(alice_address.clone()), 14);
+        assert_eq!(bc.unspent_outputs(&alice_address).len(), 0);
+        assert!(!bc.mempool.contains(&txid));
+
+        // Back to before the block, with the transaction waiting to be mined again
+        assert_eq!(bc.rollback_to(2).unwrap(), 1);
+        assert_eq!(bc.calculate_total_amount(alice_address.clone()), 40);
+        assert_eq!(bc.calculate_total_amount(bob_address), 0);
+        assert_eq!(bc.unspent_outputs(&alice_address), outputs);
+        assert_eq!(bc.state.next_nonce(alice_address.as_bytes()), 0);
+        assert_eq!(bc.next_nonce(&alice_address), 1);
+        assert!(bc.mempool.contains(&txid));
+
+        // Without the block that paid for it, the transaction has nothing to spend
+        assert_eq!(bc.rollback_to(1).unwrap(), 1);
+        assert!(!bc.mempool.contains(&txid));
+        assert_eq!(bc.next_nonce(&alice_address), 0);
+        assert_eq!(bc.calculate_total_amount(alice_address), 20);
+    }
+
+    // Mines a block with only a coinbase to `wallet` on `previous`, at `height`, without
+    // connecting it
+    fn mine_side_block(bc: &BlockChain, previous: Vec<u8>, height: u64, wallet: &Wallet) -> Block {
+        let subsidy = bc.params.block_subsidy(height);
+        let address = wallet.get_address().into_bytes();
+        let mut block = Block::new(0, previous, bc.next_bits());
+        block.transactions =
+            vec![
+                UtxoTransaction::coinbase(height, vec![TxOutput::new(subsidy, address)])
+                    .serialization(),
+            ];
+        block.header.merkle_root = merkle::merkle_root(&block.transactions);
+        bc.miner.begin_block();
+        BlockChain::mine_block(&bc.miner, &mut block).unwrap();
+        block
+    }
+
+    #[test]
+    fn the_branch_with_more_work_takes_over() {
+        let mut bc = regtest_chain();
+        let [alice, bob, dave, erin] = [0; 4].map(|_| Wallet::new(bc.params()));
+        fund(&mut bc, &alice);
+        let fork = bc.last_block().hash();
+
+        // The active branch confirms a payment and pays a reward that is spent in the pool
+        let payment = alice.sign_transaction(&bob.get_address(), 5, 1, 0);
+        let payment_id = payment.to_account_transaction().unwrap().id();
+        bc.add_transaction(&payment).unwrap();
+        let transactions = vec![
+            coinbase(&bc, &dave.get_address()),
+            payment.to_account_transaction().unwrap().serialization(),
+        ];
+        let tip = bc.last_block().hash();
+        bc.create_block(0, tip, transactions).unwrap();
+        let spend = spend_outputs(&bc, &dave, &bob);
+        bc.add_transaction(&spend).unwrap();
+        assert_eq!(bc.calculate_total_amount(bob.get_address()), 5);
+
+        // A branch of the same length is only kept on the side
+        let first = mine_side_block(&bc, fork, 2, &erin);
+        let second = mine_side_block(&bc, first.hash(), 3, &erin);
+        assert_eq!(bc.accept_block(first).unwrap(), BlockAcceptance::SideBranch);
+        assert_eq!(bc.height(), 2);
+        assert_eq!(
+            bc.accept_block(second.clone()).unwrap(),
+            BlockAcceptance::Reorganized {
+                disconnected: 1,
+                connected: 2
+            }
+        );
+        assert_eq!(bc.last_block().hash(), second.hash());
+
+        // Balances follow the new branch, the payment waits to be mined again and the
+        // spend of the reward that is gone is dropped
+        assert_eq!(bc.calculate_total_amount(alice.get_address()), 20);
+        assert_eq!(bc.calculate_total_amount(bob.get_address()), 0);
+        assert_eq!(bc.calculate_total_amount(dave.get_address()), 0);
+        assert_eq!(bc.calculate_total_amount(erin.get_address()), 40);
+        assert!(bc.mempool.contains(&payment_id));
+        assert!(!bc.mempool.contains(&spend.id()));
+        assert_eq!(bc.next_nonce(&alice.get_address()), 1);
+
+        bc.mining().unwrap();
+        assert_eq!(bc.calculate_total_amount(bob.get_address()), 5);
+        assert!(bc.mempool.is_empty());
+    }
+
+    #[test]
+    fn an_invalid_branch_leaves_the_active_chain_as_it_was() {
+        let mut bc = regtest_chain();
+        let [alice, bob, erin] = [0; 3].map(|_| Wallet::new(bc.params()));
+        fund(&mut bc, &alice);
+        let fork = bc.last_block().hash();
+        fund(&mut bc, &alice);
+        let tip = bc.last_block().hash();
+        let payment = alice.sign_transaction(&bob.get_address(), 25, 1, 0);
+        bc.add_transaction(&payment).unwrap();
+
+        let first = mine_side_block(&bc, fork, 2, &erin);
+        let mut second = mine_side_block(&bc, first.hash(), 3, &erin);
+        // Erin only has her first reward to send
+        second.transactions.push(send(&erin, &bob, 100, 1, 0));
+        second.header.merkle_root = merkle::merkle_root(&second.transactions);
+        bc.miner.begin_block();
+        BlockChain::mine_block(&bc.miner, &mut second).unwrap();
+        assert_eq!(bc.accept_block(first).unwrap(), BlockAcceptance::SideBranch);
+        assert!(matches!(
+            bc.accept_block(second).unwrap(),
+            BlockAcceptance::Rejected(ChainValidationError::NegativeBalance { .. })
+        ));
+
+        assert_eq!(bc.last_block().hash(), tip);
+        assert_eq!(bc.calculate_total_amount(alice.get_address()), 40);
+        assert_eq!(bc.calculate_total_amount(erin.get_address()), 0);
+        assert!(bc
+            .mempool
+            .contains(&payment.to_account_transaction().unwrap().id()));
+    }
+
+    // A fresh block store directory, removed again when it is dropped
+    struct StoreDir(std::path::PathBuf);
+
+    impl StoreDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("chain-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            StoreDir(dir)
+        }
+    }
+
+    impl Drop for StoreDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn open(dir: &StoreDir) -> io::Result<BlockChain> {
+        let params = ChainParams::regtest();
+        let miner = Wallet::new(&params);
+        let mut bc = BlockChain::open(miner.get_address(), params, &dir.0)?;
+        bc.set_mining_threads(1);
+        Ok(bc)
+    }
+
+    #[test]
+    fn the_chain_is_rebuilt_from_the_store() {
+        let dir = StoreDir::new("reload");
+        let (alice, bob) = (
+            Wallet::new(&ChainParams::regtest()),
+            Wallet::new(&ChainParams::regtest()),
+        );
+        let mut bc = open(&dir).unwrap();
+        fund(&mut bc, &alice);
+        bc.add_transaction(&alice.sign_transaction(&bob.get_address(), 5, 1, 0))
+            .unwrap();
+        bc.mining().unwrap();
+        let tip = bc.last_block().hash();
+        drop(bc);
+
+        let bc = open(&dir).unwrap();
+        assert_eq!(bc.height(), 2);
+        assert_eq!(bc.last_block().hash(), tip);
+        assert_eq!(bc.calculate_total_amount(alice.get_address()), 14);
+        assert_eq!(bc.calculate_total_amount(bob.get_address()), 5);
+        assert_eq!(bc.next_nonce(&alice.get_address()), 1);
+    }
+
+    #[test]
+    fn a_tampered_store_does_not_load() {
+        let dir = StoreDir::new("tampered");
+        let alice = Wallet::new(&ChainParams::regtest());
+        let mut bc = open(&dir).unwrap();
+        fund(&mut bc, &alice);
+        fund(&mut bc, &alice);
+        // Claim a bigger second reward for someone else without mining the block again
+        let mut forged = bc.chain[2].clone();
+        let mallory = Wallet::new(bc.params());
+        forged.transactions[0] = UtxoTransaction::coinbase(
+            2,
+            vec![TxOutput::new(1_000, mallory.get_address().into_bytes())],
+        )
+        .serialization();
+        forged.header.merkle_root = merkle::merkle_root(&forged.transactions);
+        let store = bc.store.clone().unwrap();
+        store.truncate(2).unwrap();
+        store.append(&forged).unwrap();
+        drop(bc);
+
+        let error = open(&dir).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_failed_write_leaves_the_chain_as_it_was() {
+        let dir = StoreDir::new("failed-write");
+        let (alice, bob) = (
+            Wallet::new(&ChainParams::regtest()),
+            Wallet::new(&ChainParams::regtest()),
+        );
+        let mut bc = open(&dir).unwrap();
+        fund(&mut bc, &alice);
+        let send = alice.sign_transaction(&bob.get_address(), 5, 1, 0);
+        bc.add_transaction(&send).unwrap();
+        let tip = bc.last_block().hash();
+
+        // The store has gone away under the node
+        std::fs::remove_dir_all(&dir.0).unwrap();
+        assert!(matches!(bc.mining(), Err(BlockChainError::Storage(_))));
+        assert_eq!(bc.last_block().hash(), tip);
+        assert_eq!(bc.calculate_total_amount(alice.get_address()), 20);
+        assert_eq!(bc.mempool.len(), 1);
+        assert!(matches!(
+            bc.rollback_to(0),
+            Err(BlockChainError::Storage(_))
+        ));
+        assert_eq!(bc.height(), 1);
+    }
+
+    #[test]
+    fn a_reorganization_is_written_to_the_store() {
+        let dir = StoreDir::new("reorganization");
+        let (alice, erin) = (
+            Wallet::new(&ChainParams::regtest()),
+            Wallet::new(&ChainParams::regtest()),
+        );
+        let mut bc = open(&dir).unwrap();
+        fund(&mut bc, &alice);
+        let fork = bc.last_block().hash();
+        fund(&mut bc, &alice);
+        let first = mine_side_block(&bc, fork, 2, &erin);
+        let second = mine_side_block(&bc, first.hash(), 3, &erin);
+        bc.accept_block(first).unwrap();
+        assert!(matches!(
+            bc.accept_block(second.clone()).unwrap(),
+            BlockAcceptance::Reorganized { .. }
+        ));
+        drop(bc);
+
+        let bc = open(&dir).unwrap();
+        assert_eq!(bc.last_block().hash(), second.hash());
+        assert_eq!(bc.calculate_total_amount(alice.get_address()), 20);
+        assert_eq!(bc.calculate_total_amount(erin.get_address()), 40);
+    }
+
+    #[test]
+    fn blocks_are_only_created_on_the_tip() {
+        let mut bc = regtest_chain();
+        let alice = Wallet::new(bc.params());
+        let genesis = bc.last_block().hash();
+        fund(&mut bc, &alice);
+        let tip = bc.last_block().hash();
+
+        let transactions = vec![coinbase(&bc, &alice.get_address())];
+        assert!(matches!(
+            bc.create_block(0, genesis, transactions),
+            Err(BlockChainError::InvalidBlock {
+                height: 2,
+                reason: ChainValidationError::PreviousHashMismatch { .. }
+            })
+        ));
+        assert_eq!(bc.last_block().hash(), tip);
+        assert_eq!(bc.calculate_total_amount(alice.get_address()), 20);
+    }
+
+    // Mines a block with only a coinbase to `wallet` on the tip, stamped `time_stamp`
+    fn mine_stamped(bc: &BlockChain, wallet: &Wallet, time_stamp: u128) -> Block {
+        let mut block = Block::new(0, bc.last_block().hash(), bc.next_bits());
+        block.header.time_stamp = time_stamp;
+        block.transactions = vec![coinbase(bc, &wallet.get_address())];
+        block.header.merkle_root = merkle::merkle_root(&block.transactions);
+        bc.miner.begin_block();
+        BlockChain::mine_block(&bc.miner, &mut block).unwrap();
+        block
+    }
+
+    #[test]
+    fn blocks_have_to_come_after_the_median_time_past() {
+        let mut bc = regtest_chain();
+        let alice = Wallet::new(bc.params());
+        for _ in 0..3 {
+            fund(&mut bc, &alice);
+        }
+        // The median of the genesis block and the three after it is the third block
+        let median = bc.median_time_past(&bc.chain);
+        assert_eq!(median, bc.chain[2].header.time_stamp);
+
+        let stale = mine_stamped(&bc, &alice, median);
+        assert_eq!(
+            bc.accept_block(stale).unwrap(),
+            BlockAcceptance::Rejected(ChainValidationError::TimestampTooOld {
+                median,
+                found: median
+            })
+        );
+        // Older than the tip is fine as long as it is past the median
+        let behind_tip = mine_stamped(&bc, &alice, median + 1);
+        assert!(behind_tip.header.time_stamp < bc.last_block().header.time_stamp);
+        assert_eq!(
+            bc.accept_block(behind_tip).unwrap(),
+            BlockAcceptance::Extended
+        );
+    }
+
+    #[test]
+    fn only_new_blocks_are_held_against_the_clock() {
+        let dir = StoreDir::new("future-drift");
+        let alice = Wallet::new(&ChainParams::regtest());
+        let mut bc = open(&dir).unwrap();
+        let drift = bc.params().max_future_drift;
+
+        let ahead = BlockChain::now() + 2 * drift;
+        let future = mine_stamped(&bc, &alice, ahead);
+        assert!(matches!(
+            bc.accept_block(future.clone()).unwrap(),
+            BlockAcceptance::Rejected(ChainValidationError::TimestampTooFarAhead { .. })
+        ));
+        let near = mine_stamped(&bc, &alice, BlockChain::now() + drift / 2);
+        assert_eq!(bc.accept_block(near).unwrap(), BlockAcceptance::Extended);
+
+        // A block the clock allowed when it came in, as if the clock went back since
+        let store = bc.store.clone().unwrap();
+        let late = mine_stamped(&bc, &alice, ahead);
+        store.append(&late).unwrap();
+        drop(bc);
+        let bc = open(&dir).unwrap();
+        assert_eq!(bc.height(), 2);
+        assert_eq!(bc.last_block().hash(), late.hash());
+    }
+
+    #[test]
+    fn a_store_with_unsigned_transactions_does_not_load() {
+        let dir = StoreDir::new("legacy");
+        let (alice, bob) = (
+            Wallet::new(&ChainParams::regtest()),
+            Wallet::new(&ChainParams::regtest()),
+        );
+        let mut bc = open(&dir).unwrap();
+        fund(&mut bc, &alice);
+        // Anyone could have written a transaction of the legacy layout in alice's name
+        let legacy = Transaction::new(
+            alice.get_address().into_bytes(),
+            bob.get_address().into_bytes(),
+            5,
+            1,
+            0,
+        );
+        let mut block = mine_stamped(&bc, &alice, BlockChain::now());
+        block.transactions.push(legacy.serialization());
+        block.header.merkle_root = merkle::merkle_root(&block.transactions);
+        BlockChain::mine_block(&bc.miner, &mut block).unwrap();
+        bc.store.clone().unwrap().append(&block).unwrap();
+        drop(bc);
+
+        let error = open(&dir).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let expected = ChainValidationError::InvalidSignature {
+            address: alice.get_address().into_bytes(),
+        };
+        assert!(error.to_string().contains(&expected.to_string()));
+    }
+
+    #[test]
+    fn the_coinbase_may_claim_the_subsidy_and_the_fees_only() {
+        let mut bc = regtest_chain();
+        let (alice, bob) = (Wallet::new(bc.params()), Wallet::new(bc.params()));
+        fund(&mut bc, &alice);
+        let height = bc.chain.len() as u64;
+        let reward = |value| {
+            UtxoTransaction::coinbase(
+                height,
+                vec![TxOutput::new(value, bob.get_address().into_bytes())],
+            )
+            .serialization()
+        };
+        let payment = send(&alice, &bob, 5, 3, 0);
+
+        let greedy =
+            bc.assemble_block(0, bc.last_block().hash(), vec![reward(24), payment.clone()]);
+        assert!(matches!(
+            greedy,
+            Err(BlockChainError::InvalidBlock {
+                reason: ChainValidationError::ExcessiveCoinbase {
+                    allowed: 23,
+                    found: 24
+                },
+                ..
+            })
+        ));
+        let tip = bc.last_block().hash();
+        bc.create_block(0, tip, vec![reward(23), payment]).unwrap();
+        assert_eq!(bc.calculate_total_amount(bob.get_address()), 28);
+        assert_eq!(bc.circulating_supply(2), Some(40));
+    }
+
+    // A mainnet chain of `count` unmined blocks, `gap` nanoseconds apart, all at `bits`
+    fn spaced_blocks(count: usize, gap: u128, bits: u32) -> (BlockChain, Vec<Block>) {
+        let params = ChainParams::mainnet();
+        let bc = BlockChain::new(Wallet::new(&params).get_address(), params);
+        let mut blocks = vec![bc.chain[0].clone()];
+        while blocks.len() < count {
+            let previous = blocks.last().unwrap();
+            let mut block = Block::new(0, previous.hash(), bits);
+            block.header.time_stamp = previous.header.time_stamp + gap;
+            blocks.push(block);
+        }
+        (bc, blocks)
+    }
+
+    #[test]
+    fn the_target_follows_the_block_time() {
+        let params = ChainParams::mainnet();
+        let initial = Target::from_compact(params.initial_bits).unwrap();
+        let interval = params.retarget_interval;
+
+        // On schedule the target stays, in between retargets it is not even looked at
+        let (bc, blocks) = spaced_blocks(interval, params.target_block_time, params.initial_bits);
+        assert_eq!(bc.required_bits(&blocks, interval), params.initial_bits);
+        let (bc, blocks) = spaced_blocks(interval - 1, 1, params.initial_bits);
+        assert_eq!(bc.required_bits(&blocks, interval - 1), params.initial_bits);
+
+        // Twice as slow makes it twice as easy
+        let (bc, blocks) =
+            spaced_blocks(interval, 2 * params.target_block_time, params.initial_bits);
+        assert_eq!(
+            bc.required_bits(&blocks, interval),
+            initial.mul_div(2, 1).to_compact()
+        );
+    }
+
+    #[test]
+    fn a_retarget_is_clamped() {
+        let params = ChainParams::mainnet();
+        let initial = Target::from_compact(params.initial_bits).unwrap();
+        let factor = params.max_adjustment_factor as u64;
+        let interval = params.retarget_interval;
+
+        // Blocks stamped all at once only make it `factor` times harder
+        let (bc, blocks) = spaced_blocks(interval, 0, params.initial_bits);
+        assert_eq!(
+            bc.required_bits(&blocks, interval),
+            initial.mul_div(1, factor).to_compact()
+        );
+        // And a whole day per block only `factor` times easier
+        let day = 24 * 60 * 60 * 1_000_000_000;
+        let (bc, blocks) = spaced_blocks(interval, day, params.initial_bits);
+        assert_eq!(
+            bc.required_bits(&blocks, interval),
+            initial.mul_div(factor, 1).to_compact()
+        );
+        // Never easier than the proof of work limit
+        let (bc, blocks) = spaced_blocks(interval, day, params.pow_limit_bits);
+        assert_eq!(bc.required_bits(&blocks, interval), params.pow_limit_bits);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::blockchain::block_and_blockchain::Block;
+use crate::blockchain::transaction::Transaction;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Lookup tables from block and transaction properties to heights in the active chain.
+/// Height itself needs no table, it is the position in `BlockChain.chain`.
+#[derive(Debug, Clone, Default)]
+pub struct BlockIndex {
+    by_hash: HashMap<Vec<u8>, usize>,
+    by_previous_hash: HashMap<Vec<u8>, usize>,
+    by_nonce: HashMap<i32, Vec<usize>>,
+    // Ordered so a range of timestamps can be looked up
+    by_timestamp: BTreeMap<u128, Vec<usize>>,
+    // Keyed by transaction ID
+    by_transaction: HashMap<Vec<u8>, Vec<usize>>,
+}
+
+fn index_height<K: Hash + Eq>(map: &mut HashMap<K, Vec<usize>>, key: K, height: usize) {
+    map.entry(key).or_default().push(height);
+}
+
+fn unindex_height<K: Hash + Eq>(map: &mut HashMap<K, Vec<usize>>, key: &K, height: usize) {
+    if let Some(heights) = map.get_mut(key) {
+        heights.retain(|h| *h != height);
+        if heights.is_empty() {
+            map.remove(key);
+        }
+    }
+}
+
+impl BlockIndex {
+    pub fn new() -> Self {
+        BlockIndex::default()
+    }
+
+    pub fn insert(&mut self, height: usize, block: &Block) {
+        let header = block.header();
+        self.by_hash.insert(block.hash(), height);
+        self.by_previous_hash
+            .insert(header.previous_hash().to_vec(), height);
+        index_height(&mut self.by_nonce, header.nonce(), height);
+        self.by_timestamp
+            .entry(header.time_stamp())
+            .or_default()
+            .push(height);
+        for tx in block.transactions() {
+            index_height(&mut self.by_transaction, Transaction::id_of(tx), height);
+        }
+    }
+
+    // Undoes `insert`, for blocks disconnected from the active chain
+    pub fn remove(&mut self, height: usize, block: &Block) {
+        let header = block.header();
+        self.by_hash.remove(&block.hash());
+        self.by_previous_hash.remove(header.previous_hash());
+        unindex_height(&mut self.by_nonce, &header.nonce(), height);
+        if let Some(heights) = self.by_timestamp.get_mut(&header.time_stamp()) {
+            heights.retain(|h| *h != height);
+            if heights.is_empty() {
+                self.by_timestamp.remove(&header.time_stamp());
+            }
+        }
+        for tx in block.transactions() {
+            unindex_height(&mut self.by_transaction, &Transaction::id_of(tx), height);
+        }
+    }
+
+    pub fn height_of_hash(&self, hash: &[u8]) -> Option<usize> {
+        self.by_hash.get(hash).copied()
+    }
+
+    // Height of the block built on top of the block with hash `previous_hash`
+    pub fn height_of_previous_hash(&self, previous_hash: &[u8]) -> Option<usize> {
+        self.by_previous_hash.get(previous_hash).copied()
+    }
+
+    pub fn heights_of_nonce(&self, nonce: i32) -> &[usize] {
+        self.by_nonce.get(&nonce).map_or(&[], |h| h.as_slice())
+    }
+
+    // Heights of every block stamped between `from` and `to`, both included
+    pub fn heights_in_time_range(&self, from: u128, to: u128) -> Vec<usize> {
+        if from > to {
+            return Vec::new();
+        }
+        self.by_timestamp
+            .range(from..=to)
+            .flat_map(|(_, heights)| heights.iter().copied())
+            .collect()
+    }
+
+    pub fn heights_of_transaction(&self, txid: &[u8]) -> &[usize] {
+        self.by_transaction.get(txid).map_or(&[], |h| h.as_slice())
+    }
+}
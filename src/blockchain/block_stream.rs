@@ -0,0 +1,151 @@
+use crate::blockchain::block_and_blockchain::{Block, Serialization};
+use crate::blockchain::encoding::{DecodeError, MAX_BLOCK_LENGTH};
+use std::io::{self, Read, Write};
+
+// Start of every block stream, followed by `STREAM_VERSION`
+const MAGIC: &[u8; 4] = b"BLKS";
+const STREAM_VERSION: u8 = 1;
+
+/// Writes blocks one after the other, so a whole chain can be exported or sent to another
+/// node without building its encoding in memory.
+///
+/// The stream starts with `MAGIC` and a version byte. Each block follows as its length
+/// (big endian u64) and its binary serialization, and the stream ends after the last one.
+pub struct BlockWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> BlockWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[STREAM_VERSION])?;
+        Ok(BlockWriter { writer })
+    }
+
+    pub fn write_block(&mut self, block: &Block) -> io::Result<()> {
+        let bytes = block.serialization();
+        self.writer.write_all(&(bytes.len() as u64).to_be_bytes())?;
+        self.writer.write_all(&bytes)
+    }
+
+    // Flushes the stream and hands back the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads back the blocks of a stream written by `BlockWriter`, one at a time.
+pub struct BlockReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> BlockReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut start = [0_u8; 5];
+        reader.read_exact(&mut start)?;
+        if &start[..4] != MAGIC || start[4] != STREAM_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a block stream or an unsupported version of it",
+            ));
+        }
+        Ok(BlockReader { reader })
+    }
+
+    // The next block, or None once the stream ends cleanly between two blocks
+    pub fn read_block(&mut self) -> io::Result<Option<Block>> {
+        let mut length = [0_u8; 8];
+        let mut filled = 0;
+        while filled < length.len() {
+            match self.reader.read(&mut length[filled..])? {
+                0 if filled == 0 => return Ok(None),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => filled += n,
+            }
+        }
+        // The length comes from the other end, it is checked before allocating for it
+        let length = u64::from_be_bytes(length);
+        if length > MAX_BLOCK_LENGTH as u64 {
+            return Err(invalid_data(DecodeError::TooLong {
+                length,
+                limit: MAX_BLOCK_LENGTH,
+            }));
+        }
+        let mut bytes = vec![0_u8; length as usize];
+        self.reader.read_exact(&mut bytes)?;
+        Block::deserialization(bytes)
+            .map(Some)
+            .map_err(invalid_data)
+    }
+}
+
+fn invalid_data(error: DecodeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+impl<R: Read> Iterator for BlockReader<R> {
+    type Item = io::Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_block().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::chain_params::ChainParams;
+
+    fn stream(blocks: &[Block]) -> Vec<u8> {
+        let mut writer = BlockWriter::new(Vec::new()).unwrap();
+        for block in blocks {
+            writer.write_block(block).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let genesis = Block::genesis(&ChainParams::regtest());
+        let bytes = stream(&[genesis.clone(), genesis.clone()]);
+        let blocks = BlockReader::new(bytes.as_slice())
+            .unwrap()
+            .collect::<io::Result<Vec<Block>>>()
+            .unwrap();
+        assert_eq!(blocks, vec![genesis.clone(), genesis]);
+    }
+
+    #[test]
+    fn truncated_block_fails() {
+        let mut bytes = stream(&[Block::genesis(&ChainParams::regtest())]);
+        bytes.pop();
+        let mut reader = BlockReader::new(bytes.as_slice()).unwrap();
+        assert!(reader.read_block().is_err());
+    }
+
+    #[test]
+    fn block_with_trailing_bytes_fails() {
+        let block = Block::genesis(&ChainParams::regtest()).serialization();
+        let mut bytes = stream(&[]);
+        bytes.extend((block.len() as u64 + 1).to_be_bytes());
+        bytes.extend(block);
+        bytes.push(0);
+        let mut reader = BlockReader::new(bytes.as_slice()).unwrap();
+        assert!(reader.read_block().is_err());
+    }
+
+    #[test]
+    fn oversized_frame_is_refused_before_reading_it() {
+        let mut bytes = stream(&[]);
+        bytes.extend(u64::MAX.to_be_bytes());
+        let mut reader = BlockReader::new(bytes.as_slice()).unwrap();
+        let error = reader.read_block().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn wrong_magic_is_refused() {
+        assert!(BlockReader::new(&b"XXXX\x01"[..]).is_err());
+    }
+}
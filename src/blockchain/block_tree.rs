@@ -0,0 +1,97 @@
+use crate::blockchain::block_and_blockchain::Block;
+use crate::blockchain::target::Target;
+use crate::blockchain::validation::ChainValidationError;
+use std::collections::HashMap;
+
+/// What happened to a block handed to `BlockChain::accept_block`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockAcceptance {
+    AlreadyKnown,
+    // The block was appended to the active chain
+    Extended,
+    // The block was stored on a branch with less work than the active chain
+    SideBranch,
+    // The block's branch overtook the active chain
+    Reorganized {
+        disconnected: usize,
+        connected: usize,
+    },
+    Rejected(ChainValidationError),
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockNode {
+    pub block: Block,
+    pub height: usize,
+    // Work of this block and all its ancestors
+    pub chain_work: u128,
+}
+
+/// Every block we know of, on the active chain or on a side branch, keyed by hash.
+#[derive(Debug, Clone, Default)]
+pub struct BlockTree {
+    nodes: HashMap<Vec<u8>, BlockNode>,
+}
+
+impl BlockTree {
+    pub fn new() -> Self {
+        BlockTree {
+            nodes: HashMap::new(),
+        }
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.nodes.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &[u8]) -> Option<&BlockNode> {
+        self.nodes.get(hash)
+    }
+
+    pub fn block_work(block: &Block) -> u128 {
+        Target::from_compact(block.header().bits())
+            .map(|target| target.work())
+            .unwrap_or(0)
+    }
+
+    // Adds a block whose parent is already in the tree, or the genesis block of an empty tree
+    pub fn insert(&mut self, block: Block) -> Option<&BlockNode> {
+        let work = BlockTree::block_work(&block);
+        let (height, parent_work) = match self.nodes.get(block.header().previous_hash()) {
+            Some(parent) => (parent.height + 1, parent.chain_work),
+            None if self.nodes.is_empty() => (0, 0),
+            None => return None,
+        };
+        let hash = block.hash();
+        self.nodes.insert(
+            hash.clone(),
+            BlockNode {
+                block,
+                height,
+                chain_work: parent_work.saturating_add(work),
+            },
+        );
+        self.nodes.get(&hash)
+    }
+
+    pub fn remove(&mut self, hash: &[u8]) -> Option<BlockNode> {
+        self.nodes.remove(hash)
+    }
+
+    // Blocks from the tip of a branch back to, but excluding, the first block for which
+    // `is_on_active_chain` holds. Returned in chain order, oldest first.
+    pub fn branch(
+        &self,
+        tip: &[u8],
+        is_on_active_chain: impl Fn(&BlockNode) -> bool,
+    ) -> Option<Vec<&BlockNode>> {
+        let mut branch = Vec::<&BlockNode>::new();
+        let mut node = self.nodes.get(tip)?;
+        while !is_on_active_chain(node) {
+            branch.push(node);
+            node = self.nodes.get(node.block.header().previous_hash())?;
+        }
+        branch.reverse();
+        Some(branch)
+    }
+}
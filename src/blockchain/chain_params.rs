@@ -0,0 +1,190 @@
+/// The rules a network runs by. Nodes and wallets on the same network have to use the
+/// same parameters, so each network is a preset rather than something to tune per node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainParams {
+    pub name: &'static str,
+    // Byte put in front of the public key hash in an address
+    pub address_version: u8,
+    // Target of the genesis block
+    pub initial_bits: u32,
+    // Easiest target a block may ever be mined at
+    pub pow_limit_bits: u32,
+    // The target is recomputed every `retarget_interval` blocks
+    pub retarget_interval: usize,
+    // Desired time between two blocks, in nanoseconds like Block.time_stamp
+    pub target_block_time: u128,
+    // A single retarget can make mining at most this many times easier or harder
+    pub max_adjustment_factor: u128,
+    // Coins created by each block at the start, on top of the fees its miner collects
+    pub initial_reward: u64,
+    // The reward is halved every `halving_interval` blocks
+    pub halving_interval: u64,
+    // No more coins than this ever exist, the premine included
+    pub max_supply: u64,
+    // A block has to be stamped later than the median of this many blocks before it
+    pub median_time_span: usize,
+    // And at most this far ahead of the local clock, in nanoseconds
+    pub max_future_drift: u128,
+    // Most bytes of serialized transactions a block may carry, the coinbase included
+    pub max_block_bytes: usize,
+    // Timestamp of the genesis block, in nanoseconds since the epoch
+    pub genesis_time_stamp: u128,
+    // Coins the genesis block creates out of nothing, as (address, value) pairs
+    pub premine: Vec<(&'static str, u64)>,
+    // Hex encoded hash of the genesis block built from the fields above. Nodes refuse to
+    // start if their genesis block does not hash to it.
+    pub genesis_hash: &'static str,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        ChainParams {
+            name: "mainnet",
+            address_version: 0x00,
+            // A hash has to start with 16 zero bits
+            initial_bits: 0x1f00_ffff,
+            pow_limit_bits: 0x2000_ffff,
+            retarget_interval: 10,
+            target_block_time: 10_000_000_000,
+            max_adjustment_factor: 4,
+            initial_reward: 20,
+            halving_interval: 100_000,
+            max_supply: 4_000_000,
+            median_time_span: 11,
+            // Two hours
+            max_future_drift: 7_200_000_000_000,
+            max_block_bytes: 100_000,
+            genesis_time_stamp: 1_723_593_600_000_000_000,
+            premine: Vec::new(),
+            genesis_hash: "43e6eeb64a7a9f5bf53777cfe25adafda064bfe4bc40390aaaa8186019344558",
+        }
+    }
+
+    // Same rules as mainnet with its own addresses, so coins of the two never mix
+    pub fn testnet() -> Self {
+        ChainParams {
+            name: "testnet",
+            address_version: 0x6f,
+            genesis_time_stamp: 1_723_680_000_000_000_000,
+            genesis_hash: "e11046706abb589adfbc8ba10b20d70dd81a02cd0f48707d1eeace5730656077",
+            ..ChainParams::mainnet()
+        }
+    }
+
+    // For local testing: blocks are mined at the easiest target and it never retargets
+    pub fn regtest() -> Self {
+        ChainParams {
+            name: "regtest",
+            address_version: 0x6f,
+            initial_bits: 0x2000_ffff,
+            retarget_interval: usize::MAX,
+            halving_interval: 150,
+            genesis_time_stamp: 1_723_766_400_000_000_000,
+            genesis_hash: "86ba7e79b7d5e0fe4d2eda6b7393c1e1717bc6922e7d0b331ba743dee7df8c01",
+            ..ChainParams::mainnet()
+        }
+    }
+
+    // Coins created up to and including the block at `height` if every miner claims the
+    // full reward, the premine included
+    pub fn scheduled_supply(&self, height: u64) -> u64 {
+        let premine: u64 = self.premine.iter().map(|(_, value)| value).sum();
+        let mut supply = premine.min(self.max_supply);
+        // 1. Walk the halving eras, the first one starts right after the genesis block
+        let mut era_start = 1_u64;
+        let mut reward = self.initial_reward;
+        while era_start <= height && reward > 0 && supply < self.max_supply {
+            let era_end = era_start
+                .saturating_add(self.halving_interval - 1)
+                .min(height);
+            let blocks = era_end - era_start + 1;
+            // 2. The last coins are cut short so the cap is never passed
+            supply = supply
+                .saturating_add(blocks.saturating_mul(reward))
+                .min(self.max_supply);
+            era_start = era_start.saturating_add(self.halving_interval);
+            reward /= 2;
+        }
+        supply
+    }
+
+    // Most coins the block at `height` may create, the genesis block creates only the premine
+    pub fn block_subsidy(&self, height: u64) -> u64 {
+        if height == 0 {
+            return 0;
+        }
+        self.scheduled_supply(height) - self.scheduled_supply(height - 1)
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "mainnet" => Some(ChainParams::mainnet()),
+            "testnet" => Some(ChainParams::testnet()),
+            "regtest" => Some(ChainParams::regtest()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block_and_blockchain::Block;
+
+    #[test]
+    fn every_preset_builds_its_own_genesis_block() {
+        let presets = [
+            ChainParams::mainnet(),
+            ChainParams::testnet(),
+            ChainParams::regtest(),
+        ];
+        for params in presets.iter() {
+            assert_eq!(
+                hex::encode(Block::genesis(params).hash()),
+                params.genesis_hash,
+                "{}",
+                params.name
+            );
+            assert_eq!(ChainParams::from_name(params.name).as_ref(), Some(params));
+        }
+        // A node on one network can never take another network's blocks as its own
+        assert_ne!(presets[0].genesis_hash, presets[1].genesis_hash);
+        assert_ne!(presets[1].genesis_hash, presets[2].genesis_hash);
+        assert_ne!(presets[0].genesis_hash, presets[2].genesis_hash);
+        assert_eq!(ChainParams::from_name("simnet"), None);
+    }
+
+    fn small_schedule() -> ChainParams {
+        ChainParams {
+            initial_reward: 20,
+            halving_interval: 10,
+            max_supply: 300,
+            premine: vec![("alice", 50)],
+            ..ChainParams::regtest()
+        }
+    }
+
+    #[test]
+    fn the_reward_halves_every_interval() {
+        let params = small_schedule();
+        assert_eq!(params.block_subsidy(0), 0);
+        assert_eq!(params.block_subsidy(1), 20);
+        assert_eq!(params.block_subsidy(10), 20);
+        assert_eq!(params.block_subsidy(11), 10);
+        assert_eq!(params.scheduled_supply(0), 50);
+        assert_eq!(params.scheduled_supply(10), 250);
+    }
+
+    #[test]
+    fn the_supply_never_passes_the_cap() {
+        let params = small_schedule();
+        // The fifth block of the second era reaches the cap, nothing is created after it
+        assert_eq!(params.scheduled_supply(15), 300);
+        assert_eq!(params.block_subsidy(15), 10);
+        assert_eq!(params.block_subsidy(16), 0);
+        assert_eq!(params.scheduled_supply(u64::MAX), 300);
+        for params in [ChainParams::mainnet(), ChainParams::regtest()] {
+            assert!(params.scheduled_supply(u64::MAX) <= params.max_supply);
+        }
+    }
+}
@@ -0,0 +1,514 @@
+use crate::blockchain::block_and_blockchain::Block;
+use crate::blockchain::chain_params::ChainParams;
+use crate::blockchain::transaction::BlockTransaction;
+use crate::blockchain::utxo::{OutPoint, TxOutput, UtxoSet};
+use crate::blockchain::validation::ChainValidationError;
+use std::collections::{HashMap, HashSet};
+
+/// Balance of every address and the unspent outputs as of the tip of the active chain.
+/// It is updated one block at a time as blocks are connected and disconnected, so a
+/// balance query never has to walk the chain.
+///
+/// Both transaction kinds move the same money: an account transaction moves balance from
+/// sender to recipient, a UTXO transaction debits the owners of the outputs it spends and
+/// credits the owners of the outputs it creates.
+///
+/// The balance is what an address owns. Its unspent outputs are the part of it that can
+/// also be spent as outputs, so they never add up to more than the balance: an account
+/// transaction that takes the balance below them retires the sender's outputs, lowest
+/// outpoint first, until the rest fits. The same coins can never be spent both ways.
+#[derive(Debug, Clone)]
+pub struct ChainState {
+    balances: HashMap<Vec<u8>, i64>,
+    // Nonce the next account transaction of each sender has to carry
+    nonces: HashMap<Vec<u8>, u64>,
+    utxos: UtxoSet,
+    // Number of blocks connected, which is also the height of the next block
+    height: u64,
+    // Sum of every balance, which is every coin created so far
+    total: i64,
+    // Coins in circulation after each connected block, indexed by height
+    supply: Vec<u64>,
+    // Rules of the network, for the address version and the reward schedule
+    params: ChainParams,
+}
+
+/// What connecting a block changed in a `ChainState`, enough to disconnect it again
+/// without looking anything up.
+#[derive(Debug, Clone, Default)]
+pub struct StateUndo {
+    // Every balance change, in the order they were made
+    balance_changes: Vec<(Vec<u8>, i64)>,
+    // Next nonce of each sender before the block, None if it had none
+    nonces: Vec<(Vec<u8>, Option<u64>)>,
+    // Outputs the block spent
+    spent: Vec<(OutPoint, TxOutput)>,
+    // Outputs the block created
+    created: Vec<OutPoint>,
+}
+
+impl StateUndo {
+    pub fn balance_changes(&self) -> &[(Vec<u8>, i64)] {
+        &self.balance_changes
+    }
+
+    pub fn spent(&self) -> &[(OutPoint, TxOutput)] {
+        &self.spent
+    }
+}
+
+/// The effect of some transactions on top of a `ChainState`, without changing it. Used to
+/// check blocks before they are connected and to assemble new ones.
+pub struct StateOverlay<'a> {
+    state: &'a ChainState,
+    // Money already promised by transactions elsewhere, taken off the balances
+    pending: Option<&'a HashMap<Vec<u8>, u64>>,
+    balances: HashMap<Vec<u8>, i64>,
+    nonces: HashMap<Vec<u8>, u64>,
+    spent: HashSet<OutPoint>,
+    created: HashMap<OutPoint, TxOutput>,
+    // What the transactions applied so far leave to the miner
+    fees: u64,
+}
+
+impl<'a> StateOverlay<'a> {
+    fn new(state: &'a ChainState) -> Self {
+        StateOverlay {
+            state,
+            pending: None,
+            balances: HashMap::new(),
+            nonces: HashMap::new(),
+            spent: HashSet::new(),
+            created: HashMap::new(),
+            fees: 0,
+        }
+    }
+
+    // Takes `pending` off the balances, money already promised by transactions elsewhere.
+    // Only the addresses the transactions touch are looked up.
+    fn with_outflows(mut self, pending: &'a HashMap<Vec<u8>, u64>) -> Self {
+        self.pending = Some(pending);
+        self
+    }
+
+    pub fn balance(&self, address: &[u8]) -> i64 {
+        if let Some(balance) = self.balances.get(address) {
+            return *balance;
+        }
+        let pending = self
+            .pending
+            .and_then(|pending| pending.get(address))
+            .map_or(0, |value| i64::try_from(*value).unwrap_or(i64::MAX));
+        self.state.balance(address).saturating_sub(pending)
+    }
+
+    pub fn next_nonce(&self, address: &[u8]) -> u64 {
+        self.nonces
+            .get(address)
+            .copied()
+            .unwrap_or_else(|| self.state.next_nonce(address))
+    }
+
+    pub fn fees(&self) -> u64 {
+        self.fees
+    }
+
+    fn unspent(&self, outpoint: &OutPoint) -> Option<&TxOutput> {
+        if self.spent.contains(outpoint) {
+            return None;
+        }
+        self.created
+            .get(outpoint)
+            .or_else(|| self.state.utxos.get(outpoint))
+    }
+
+    // Applies `tx` if it is valid on top of everything applied so far, and leaves the
+    // overlay untouched otherwise. Balances are i64, so no amount may go past i64::MAX.
+    pub fn apply(&mut self, tx: &BlockTransaction) -> Result<(), ChainValidationError> {
+        let mut deltas = Vec::<(Vec<u8>, i64)>::new();
+        let mut spent = Vec::<OutPoint>::new();
+        let mut created = Vec::<(OutPoint, TxOutput)>::new();
+        let mut fee = 0_u64;
+        match tx {
+            BlockTransaction::Account(tx) => {
+                // Only the owner of the sender's address can spend from it. Legacy
+                // transactions carry no key, anyone could have written them, so they
+                // never pass.
+                if !tx.verify(self.state.params.address_version) {
+                    return Err(ChainValidationError::InvalidSignature {
+                        address: tx.sender_address.clone(),
+                    });
+                }
+                // Each sender's transactions go in order, and only once
+                let expected = self.next_nonce(&tx.sender_address);
+                if tx.nonce != expected {
+                    return Err(ChainValidationError::UnexpectedNonce {
+                        address: tx.sender_address.clone(),
+                        expected,
+                        found: tx.nonce,
+                    });
+                }
+                // The debit covers the value and the fee, both fit if it does
+                let debit = tx
+                    .value
+                    .checked_add(tx.fee)
+                    .and_then(|debit| i64::try_from(debit).ok())
+                    .ok_or(ChainValidationError::ValueOverflow)?;
+                deltas.push((tx.recipient_address.clone(), tx.value as i64));
+                deltas.push((tx.sender_address.clone(), -debit));
+                fee = tx.fee;
+            }
+            BlockTransaction::Utxo(tx) => {
+                // 1. Every input has to spend an unspent output and be signed by its owner
+                let mut input_value = 0_u64;
+                for (i, input) in tx.inputs.iter().enumerate() {
+                    let outpoint = &input.previous_output;
+                    if self.spent.contains(outpoint) || spent.contains(outpoint) {
+                        return Err(ChainValidationError::DoubleSpend {
+                            txid: outpoint.txid.clone(),
+                            index: outpoint.index,
+                        });
+                    }
+                    let Some(output) = self.unspent(outpoint) else {
+                        return Err(ChainValidationError::MissingOutput {
+                            txid: outpoint.txid.clone(),
+                            index: outpoint.index,
+                        });
+                    };
+                    if !tx.verify_input(i, output, self.state.params.address_version) {
+                        return Err(ChainValidationError::InvalidInputSignature {
+                            txid: outpoint.txid.clone(),
+                            index: outpoint.index,
+                        });
+                    }
+                    spent.push(outpoint.clone());
+                    input_value = input_value
+                        .checked_add(output.value)
+                        .filter(|value| *value <= i64::MAX as u64)
+                        .ok_or(ChainValidationError::ValueOverflow)?;
+                    deltas.push((output.address.clone(), -(output.value as i64)));
+                }
+                // 2. A coinbase creates money, every other transaction only spends what it has
+                let output_value = tx
+                    .output_value()
+                    .filter(|value| *value <= i64::MAX as u64)
+                    .ok_or(ChainValidationError::ValueOverflow)?;
+                if !tx.is_coinbase() && output_value > input_value {
+                    return Err(ChainValidationError::OutputsExceedInputs {
+                        inputs: input_value,
+                        outputs: output_value,
+                    });
+                }
+                if !tx.is_coinbase() {
+                    fee = input_value - output_value;
+                }
+                let txid = tx.id();
+                for (i, output) in tx.outputs.iter().enumerate() {
+                    deltas.push((output.address.clone(), output.value as i64));
+                    created.push((OutPoint::new(txid.clone(), i as u32), output.clone()));
+                }
+            }
+        }
+        // 3. Every balance the transaction touches has to stay within 0 and i64::MAX
+        let mut balances = HashMap::<Vec<u8>, i64>::new();
+        for (address, delta) in deltas {
+            let balance = match balances.get(&address) {
+                Some(balance) => *balance,
+                None => self.balance(&address),
+            };
+            let balance = balance
+                .checked_add(delta)
+                .ok_or(ChainValidationError::ValueOverflow)?;
+            balances.insert(address, balance);
+        }
+        for (address, balance) in balances.iter() {
+            if *balance < 0 {
+                return Err(ChainValidationError::NegativeBalance {
+                    address: address.clone(),
+                    balance: *balance,
+                });
+            }
+        }
+        if let BlockTransaction::Account(tx) = tx {
+            let mut outputs: Vec<(OutPoint, u64)> = self
+                .state
+                .utxos
+                .outputs_of(&tx.sender_address)
+                .into_iter()
+                .filter(|(outpoint, _)| !self.spent.contains(outpoint))
+                .map(|(outpoint, output)| (outpoint.clone(), output.value))
+                .collect();
+            outputs.extend(
+                self.created
+                    .iter()
+                    .filter(|(outpoint, output)| {
+                        output.address == tx.sender_address && !self.spent.contains(outpoint)
+                    })
+                    .map(|(outpoint, output)| (outpoint.clone(), output.value)),
+            );
+            outputs.sort();
+            spent.extend(ChainState::retired(outputs, balances[&tx.sender_address]));
+        }
+        let fees = self
+            .fees
+            .checked_add(fee)
+            .ok_or(ChainValidationError::ValueOverflow)?;
+        // 4. Everything checks out, record it
+        if let BlockTransaction::Account(tx) = tx {
+            self.nonces.insert(tx.sender_address.clone(), tx.nonce + 1);
+        }
+        self.balances.extend(balances);
+        self.spent.extend(spent);
+        self.created.extend(created);
+        self.fees = fees;
+        Ok(())
+    }
+}
+
+impl ChainState {
+    pub fn new(params: ChainParams) -> Self {
+        ChainState {
+            balances: HashMap::new(),
+            nonces: HashMap::new(),
+            utxos: UtxoSet::new(),
+            height: 0,
+            total: 0,
+            supply: Vec::new(),
+            params,
+        }
+    }
+
+    pub fn balance(&self, address: &[u8]) -> i64 {
+        self.balances.get(address).copied().unwrap_or(0)
+    }
+
+    pub fn next_nonce(&self, address: &[u8]) -> u64 {
+        self.nonces.get(address).copied().unwrap_or(0)
+    }
+
+    pub fn utxos(&self) -> &UtxoSet {
+        &self.utxos
+    }
+
+    // Coins in circulation once the block at `height` is connected, None past the tip
+    pub fn circulating_supply(&self, height: u64) -> Option<u64> {
+        self.supply.get(height as usize).copied()
+    }
+
+    // How much each address pays into `tx`, as far as confirmed outputs go
+    pub fn outflows_of(&self, tx: &BlockTransaction) -> Vec<(Vec<u8>, u64)> {
+        match tx {
+            BlockTransaction::Account(tx) => {
+                vec![(tx.sender_address.clone(), tx.value.saturating_add(tx.fee))]
+            }
+            BlockTransaction::Utxo(tx) => tx
+                .inputs
+                .iter()
+                .filter_map(|input| self.utxos.get(&input.previous_output))
+                .map(|output| (output.address.clone(), output.value))
+                .collect(),
+        }
+    }
+
+    // What `tx` leaves to the miner. For a UTXO transaction that is whatever its inputs hold
+    // beyond its outputs, counting only inputs that spend confirmed outputs.
+    pub fn fee_of(&self, tx: &BlockTransaction) -> u64 {
+        match tx {
+            BlockTransaction::Account(tx) => tx.fee,
+            BlockTransaction::Utxo(tx) => {
+                let input_value = tx
+                    .inputs
+                    .iter()
+                    .filter_map(|input| self.utxos.get(&input.previous_output))
+                    .fold(0_u64, |sum, output| sum.saturating_add(output.value));
+                // Outputs past a u64 never get into a block, they leave nothing
+                tx.output_value()
+                    .map_or(0, |output_value| input_value.saturating_sub(output_value))
+            }
+        }
+    }
+
+    // Replays the transactions of `block` on top of the current state without changing
+    // it, failing if an output is missing or spent twice, any balance would go negative or
+    // the coinbase claims more than the reward schedule and the fees allow. A `stored`
+    // block, read back from the block store, may still decode legacy transactions, which
+    // then fail for their missing signature.
+    pub fn check_block(&self, block: &Block, stored: bool) -> Result<(), ChainValidationError> {
+        let decode = if stored {
+            BlockTransaction::try_decode_stored
+        } else {
+            BlockTransaction::try_decode
+        };
+        let mut overlay = StateOverlay::new(self);
+        let mut claimed = 0_u64;
+        for (i, t) in block.transactions().iter().enumerate() {
+            let tx = decode(t).map_err(|reason| ChainValidationError::MalformedTransaction {
+                index: i,
+                reason,
+            })?;
+            if let BlockTransaction::Utxo(utxo) = &tx {
+                if utxo.is_coinbase() {
+                    if i != 0 || utxo.coinbase_height != self.height {
+                        return Err(ChainValidationError::UnexpectedCoinbase);
+                    }
+                    claimed = utxo
+                        .output_value()
+                        .ok_or(ChainValidationError::ValueOverflow)?;
+                }
+            }
+            overlay.apply(&tx)?;
+        }
+        // The genesis block is fixed by the network, its premine is checked with its hash
+        if self.height > 0 {
+            let allowed = self
+                .params
+                .block_subsidy(self.height)
+                .checked_add(overlay.fees())
+                .ok_or(ChainValidationError::ValueOverflow)?;
+            if claimed > allowed {
+                return Err(ChainValidationError::ExcessiveCoinbase {
+                    allowed,
+                    found: claimed,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Starts an overlay on top of the current state
+    pub fn overlay(&self) -> StateOverlay<'_> {
+        StateOverlay::new(self)
+    }
+
+    // Same as `check_block` for transactions that are not in a block yet, none of which
+    // may be a coinbase. `pending` is what each address already owes to transactions
+    // waiting in the pool.
+    pub fn check_transactions(
+        &self,
+        transactions: &[Vec<u8>],
+        pending: &HashMap<Vec<u8>, u64>,
+    ) -> Result<(), ChainValidationError> {
+        let mut overlay = StateOverlay::new(self).with_outflows(pending);
+        for (i, t) in transactions.iter().enumerate() {
+            let tx = BlockTransaction::try_decode(t).map_err(|reason| {
+                ChainValidationError::MalformedTransaction { index: i, reason }
+            })?;
+            if tx.is_coinbase() {
+                return Err(ChainValidationError::UnexpectedCoinbase);
+            }
+            overlay.apply(&tx)?;
+        }
+        Ok(())
+    }
+
+    // Connects `block` and returns what it takes to disconnect it again
+    pub fn connect_block(&mut self, block: &Block) -> StateUndo {
+        let mut undo = StateUndo::default();
+        for t in block.transactions() {
+            match BlockTransaction::decode(t) {
+                BlockTransaction::Account(tx) => {
+                    self.change(&mut undo, &tx.recipient_address, tx.value as i64);
+                    self.change(&mut undo, &tx.sender_address, -((tx.value + tx.fee) as i64));
+                    if !undo
+                        .nonces
+                        .iter()
+                        .any(|(address, _)| *address == tx.sender_address)
+                    {
+                        let previous = self.nonces.get(&tx.sender_address).copied();
+                        undo.nonces.push((tx.sender_address.clone(), previous));
+                    }
+                    let outputs = self
+                        .utxos
+                        .outputs_of(&tx.sender_address)
+                        .into_iter()
+                        .map(|(outpoint, output)| (outpoint.clone(), output.value))
+                        .collect();
+                    let balance = self.balance(&tx.sender_address);
+                    for outpoint in ChainState::retired(outputs, balance) {
+                        let output = self.utxos.remove(&outpoint).unwrap();
+                        undo.spent.push((outpoint, output));
+                    }
+                    self.nonces.insert(tx.sender_address, tx.nonce + 1);
+                }
+                BlockTransaction::Utxo(tx) => {
+                    for input in tx.inputs.iter() {
+                        if let Some(output) = self.utxos.remove(&input.previous_output) {
+                            self.change(&mut undo, &output.address, -(output.value as i64));
+                            undo.spent.push((input.previous_output.clone(), output));
+                        }
+                    }
+                    let txid = tx.id();
+                    for (i, output) in tx.outputs.into_iter().enumerate() {
+                        let outpoint = OutPoint::new(txid.clone(), i as u32);
+                        self.change(&mut undo, &output.address, output.value as i64);
+                        self.utxos.insert(outpoint.clone(), output);
+                        undo.created.push(outpoint);
+                    }
+                }
+            }
+        }
+        self.supply.push(self.total as u64);
+        self.height += 1;
+        undo
+    }
+
+    // Exact inverse of `connect_block` for the tip block, given what connecting it returned
+    pub fn disconnect_block(&mut self, undo: &StateUndo) {
+        // 1. Outputs created and spent within the block were never in the set before it
+        let created: HashSet<&OutPoint> = undo.created.iter().collect();
+        for outpoint in undo.created.iter() {
+            self.utxos.remove(outpoint);
+        }
+        for (outpoint, output) in undo.spent.iter() {
+            if !created.contains(outpoint) {
+                self.utxos.insert(outpoint.clone(), output.clone());
+            }
+        }
+        // 2. Balances and nonces go back to what they were
+        for (address, delta) in undo.balance_changes.iter().rev() {
+            self.adjust(address, -delta);
+        }
+        for (address, nonce) in undo.nonces.iter() {
+            match nonce {
+                Some(nonce) => self.nonces.insert(address.clone(), *nonce),
+                None => self.nonces.remove(address),
+            };
+        }
+        self.supply.pop();
+        self.height -= 1;
+    }
+
+    // Outputs, sorted by outpoint, to retire so the rest add up to no more than `balance`.
+    // Retiring an output leaves the balance as it is, it only stops being spendable as one.
+    fn retired(outputs: Vec<(OutPoint, u64)>, balance: i64) -> Vec<OutPoint> {
+        let mut sum = outputs
+            .iter()
+            .fold(0_u64, |sum, (_, value)| sum.saturating_add(*value));
+        let balance = balance.max(0) as u64;
+        let mut retired = Vec::new();
+        for (outpoint, value) in outputs {
+            if sum <= balance {
+                break;
+            }
+            sum -= value;
+            retired.push(outpoint);
+        }
+        retired
+    }
+
+    // Adjusts a balance and records it in `undo`
+    fn change(&mut self, undo: &mut StateUndo, address: &[u8], delta: i64) {
+        self.adjust(address, delta);
+        undo.balance_changes.push((address.to_vec(), delta));
+    }
+
+    fn adjust(&mut self, address: &[u8], delta: i64) {
+        let balance = self.balances.entry(address.to_vec()).or_insert(0);
+        *balance += delta;
+        self.total += delta;
+        // Keep the map from growing with addresses that hold nothing
+        if *balance == 0 {
+            self.balances.remove(address);
+        }
+    }
+}
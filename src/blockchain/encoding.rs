@@ -0,0 +1,268 @@
+use std::fmt::Display;
+
+// Building blocks of the binary formats: fixed size big endian integers, varints, and byte
+// strings prefixed with their length. Every read checks the input is long enough, so a
+// truncated or hostile buffer is an error rather than a panic.
+
+// Longest address, public key, signature or transaction ID a decoder accepts
+pub const MAX_FIELD_LENGTH: usize = 1_024;
+// Longest serialized transaction a decoder accepts
+pub const MAX_TRANSACTION_LENGTH: usize = 1_000_000;
+// Longest serialized block a decoder accepts
+pub const MAX_BLOCK_LENGTH: usize = 4_000_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    // The input ends in the middle of a field
+    UnexpectedEnd,
+    // A length larger than anything valid, refused before allocating for it
+    TooLong { length: u64, limit: usize },
+    // Bytes left over after a complete value
+    TrailingBytes(usize),
+    UnsupportedVersion(u8),
+    // A field holding something it never can, like the wrong tag
+    Invalid(&'static str),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "the input ends in the middle of a field"),
+            DecodeError::TooLong { length, limit } => {
+                write!(f, "a length of {} is over the limit of {}", length, limit)
+            }
+            DecodeError::TrailingBytes(count) => {
+                write!(f, "{} byte(s) left over after the value", count)
+            }
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {}", version)
+            }
+            DecodeError::Invalid(reason) => write!(f, "invalid encoding: {}", reason),
+        }
+    }
+}
+
+pub fn write_bytes(bin: &mut Vec<u8>, bytes: &[u8]) {
+    bin.extend((bytes.len() as u64).to_be_bytes());
+    bin.extend(bytes);
+}
+
+fn take<'a>(bytes: &'a [u8], pos: &mut usize, length: usize) -> Result<&'a [u8], DecodeError> {
+    let end = pos.checked_add(length).ok_or(DecodeError::UnexpectedEnd)?;
+    let value = bytes.get(*pos..end).ok_or(DecodeError::UnexpectedEnd)?;
+    *pos = end;
+    Ok(value)
+}
+
+pub fn read_u8(bytes: &[u8], pos: &mut usize) -> Result<u8, DecodeError> {
+    Ok(take(bytes, pos, 1)?[0])
+}
+
+pub fn read_u32(bytes: &[u8], pos: &mut usize) -> Result<u32, DecodeError> {
+    Ok(u32::from_be_bytes(take(bytes, pos, 4)?.try_into().unwrap()))
+}
+
+pub fn read_i32(bytes: &[u8], pos: &mut usize) -> Result<i32, DecodeError> {
+    Ok(read_u32(bytes, pos)? as i32)
+}
+
+pub fn read_u64(bytes: &[u8], pos: &mut usize) -> Result<u64, DecodeError> {
+    Ok(u64::from_be_bytes(take(bytes, pos, 8)?.try_into().unwrap()))
+}
+
+pub fn read_u128(bytes: &[u8], pos: &mut usize) -> Result<u128, DecodeError> {
+    Ok(u128::from_be_bytes(
+        take(bytes, pos, 16)?.try_into().unwrap(),
+    ))
+}
+
+// A length prefixed byte string of at most `limit` bytes
+pub fn read_bytes(bytes: &[u8], pos: &mut usize, limit: usize) -> Result<Vec<u8>, DecodeError> {
+    let length = read_u64(bytes, pos)?;
+    if length > limit as u64 {
+        return Err(DecodeError::TooLong { length, limit });
+    }
+    Ok(take(bytes, pos, length as usize)?.to_vec())
+}
+
+// Unsigned LEB128: seven bits per byte, lowest first, the high bit set on every byte but
+// the last. Small values take a single byte whatever the platform.
+pub fn write_varint(bin: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bin.push(byte);
+            return;
+        }
+        bin.push(byte | 0x80);
+    }
+}
+
+pub fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<u64, DecodeError> {
+    let mut value = 0_u64;
+    let mut shift = 0;
+    loop {
+        let byte = read_u8(bytes, pos)?;
+        // The tenth byte only has room for the top bit of a u64
+        if shift == 63 && byte > 1 {
+            return Err(DecodeError::Invalid("varint overflows a u64"));
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            // Only the shortest encoding is canonical, so each value has exactly one
+            if byte == 0 && shift > 0 {
+                return Err(DecodeError::Invalid("varint is not minimally encoded"));
+            }
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+pub fn write_var_bytes(bin: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(bin, bytes.len() as u64);
+    bin.extend(bytes);
+}
+
+// A byte string behind a varint length, of at most `limit` bytes
+pub fn read_var_bytes(bytes: &[u8], pos: &mut usize, limit: usize) -> Result<Vec<u8>, DecodeError> {
+    let length = read_varint(bytes, pos)?;
+    if length > limit as u64 {
+        return Err(DecodeError::TooLong { length, limit });
+    }
+    Ok(take(bytes, pos, length as usize)?.to_vec())
+}
+
+// Fails unless the whole input was read
+pub fn finish(bytes: &[u8], pos: usize) -> Result<(), DecodeError> {
+    match bytes.len().saturating_sub(pos) {
+        0 => Ok(()),
+        left => Err(DecodeError::TrailingBytes(left)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_round_trip() {
+        for value in [
+            0,
+            1,
+            127,
+            128,
+            300,
+            16_383,
+            16_384,
+            u32::MAX as u64,
+            u64::MAX,
+        ] {
+            let mut bin = Vec::new();
+            write_varint(&mut bin, value);
+            let mut pos = 0;
+            assert_eq!(read_varint(&bin, &mut pos), Ok(value));
+            assert_eq!(pos, bin.len());
+        }
+    }
+
+    #[test]
+    fn varint_takes_one_byte_below_128() {
+        let mut bin = Vec::new();
+        write_varint(&mut bin, 127);
+        assert_eq!(bin, [0x7f]);
+    }
+
+    #[test]
+    fn varint_rejects_non_minimal_encoding() {
+        // 0 written on two bytes
+        let mut pos = 0;
+        assert!(matches!(
+            read_varint(&[0x80, 0x00], &mut pos),
+            Err(DecodeError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn varint_rejects_overflow() {
+        let mut bin = vec![0xff; 9];
+        bin.push(0x02);
+        let mut pos = 0;
+        assert!(matches!(
+            read_varint(&bin, &mut pos),
+            Err(DecodeError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn varint_rejects_truncated_input() {
+        let mut pos = 0;
+        assert_eq!(
+            read_varint(&[0x80, 0x80], &mut pos),
+            Err(DecodeError::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn var_bytes_round_trip() {
+        let mut bin = Vec::new();
+        write_var_bytes(&mut bin, b"address");
+        let mut pos = 0;
+        assert_eq!(
+            read_var_bytes(&bin, &mut pos, MAX_FIELD_LENGTH),
+            Ok(b"address".to_vec())
+        );
+        assert_eq!(finish(&bin, pos), Ok(()));
+    }
+
+    #[test]
+    fn lengths_over_the_limit_are_refused() {
+        let mut bin = Vec::new();
+        write_varint(&mut bin, MAX_FIELD_LENGTH as u64 + 1);
+        let mut pos = 0;
+        assert_eq!(
+            read_var_bytes(&bin, &mut pos, MAX_FIELD_LENGTH),
+            Err(DecodeError::TooLong {
+                length: MAX_FIELD_LENGTH as u64 + 1,
+                limit: MAX_FIELD_LENGTH
+            })
+        );
+        // A huge length is refused before anything is allocated for it
+        let mut bin = Vec::new();
+        bin.extend(u64::MAX.to_be_bytes());
+        let mut pos = 0;
+        assert!(matches!(
+            read_bytes(&bin, &mut pos, MAX_FIELD_LENGTH),
+            Err(DecodeError::TooLong { .. })
+        ));
+    }
+
+    #[test]
+    fn byte_strings_shorter_than_their_length_are_truncated() {
+        let mut bin = Vec::new();
+        write_bytes(&mut bin, b"address");
+        bin.pop();
+        let mut pos = 0;
+        assert_eq!(
+            read_bytes(&bin, &mut pos, MAX_FIELD_LENGTH),
+            Err(DecodeError::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn fixed_size_reads_fail_on_truncated_input() {
+        let mut pos = 0;
+        assert_eq!(read_u64(&[0; 7], &mut pos), Err(DecodeError::UnexpectedEnd));
+        let mut pos = 0;
+        assert_eq!(read_u32(&[0; 3], &mut pos), Err(DecodeError::UnexpectedEnd));
+        let mut pos = 0;
+        assert_eq!(read_u8(&[], &mut pos), Err(DecodeError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn finish_reports_trailing_bytes() {
+        assert_eq!(finish(&[1, 2, 3], 1), Err(DecodeError::TrailingBytes(2)));
+        assert_eq!(finish(&[1, 2, 3], 3), Ok(()));
+    }
+}
@@ -0,0 +1,433 @@
+use crate::blockchain::transaction::{BlockTransaction, Transaction};
+use crate::blockchain::utxo::OutPoint;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub transaction: Vec<u8>,
+    pub txid: Vec<u8>,
+    pub fee: u64,
+    // What each paying address owes to this transaction
+    pub outflows: Vec<(Vec<u8>, u64)>,
+    pub added: Instant,
+    // Position in arrival order
+    sequence: u64,
+}
+
+impl MempoolEntry {
+    pub fn size(&self) -> usize {
+        self.transaction.len()
+    }
+
+    // Orders entries by fee per byte, without dividing
+    pub fn cmp_fee_rate(&self, other: &MempoolEntry) -> Ordering {
+        (self.fee as u128 * other.size() as u128).cmp(&(other.fee as u128 * self.size() as u128))
+    }
+
+    // Orders entries for mining, the higher fee per byte and then the earlier arrival first
+    pub fn cmp_priority(&self, other: &MempoolEntry) -> Ordering {
+        self.cmp_fee_rate(other)
+            .then(other.sequence.cmp(&self.sequence))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MempoolRejection {
+    Duplicate,
+    // The transaction alone is bigger than the whole pool
+    TooLarge { size: usize, limit: usize },
+    // The pool is full of transactions paying at least as much per byte
+    PoolFull,
+    // The fee is below the pool's minimum fee rate for a transaction of this size
+    FeeTooLow { fee: u64, required: u64 },
+}
+
+impl Display for MempoolRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MempoolRejection::Duplicate => write!(f, "the transaction is already in the pool"),
+            MempoolRejection::TooLarge { size, limit } => write!(
+                f,
+                "the transaction is {} bytes, more than the pool limit of {}",
+                size, limit
+            ),
+            MempoolRejection::PoolFull => write!(
+                f,
+                "the pool is full and the transaction does not pay enough to replace another"
+            ),
+            MempoolRejection::FeeTooLow { fee, required } => write!(
+                f,
+                "the transaction pays a fee of {} but at least {} is required",
+                fee, required
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MempoolStats {
+    pub count: usize,
+    pub bytes: usize,
+    // How long the oldest transaction has been waiting, None for an empty pool
+    pub oldest: Option<Duration>,
+}
+
+/// Transactions waiting to be mined, keyed by transaction ID.
+///
+/// The pool holds at most `max_count` transactions and `max_bytes` bytes. When it is full
+/// a new transaction can only get in by evicting transactions paying less per byte, and
+/// transactions that were not mined within `expiry` are dropped. Every transaction has to
+/// pay at least `min_fee_rate` per 1000 bytes, rounded up, so flooding it is not free.
+#[derive(Debug, Clone)]
+pub struct Mempool {
+    entries: HashMap<Vec<u8>, MempoolEntry>,
+    bytes: usize,
+    // Sum of the outflows of every entry, per address
+    pending: HashMap<Vec<u8>, u64>,
+    // Nonces of the account transactions waiting in the pool, per sender
+    pending_nonces: HashMap<Vec<u8>, BTreeSet<u64>>,
+    // ID of the transaction spending each output spent by a transaction in the pool
+    spends: HashMap<OutPoint, Vec<u8>>,
+    // Every entry by arrival time, oldest first, so expiring does not walk the pool
+    by_age: BTreeMap<(Instant, u64), Vec<u8>>,
+    next_sequence: u64,
+    max_count: usize,
+    max_bytes: usize,
+    expiry: Duration,
+    min_fee_rate: u64,
+}
+
+impl Mempool {
+    pub fn new(max_count: usize, max_bytes: usize, expiry: Duration, min_fee_rate: u64) -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            bytes: 0,
+            pending: HashMap::new(),
+            pending_nonces: HashMap::new(),
+            spends: HashMap::new(),
+            by_age: BTreeMap::new(),
+            next_sequence: 0,
+            max_count,
+            max_bytes,
+            expiry,
+            min_fee_rate,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn contains(&self, txid: &[u8]) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &[u8]) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    // What `address` pays into transactions waiting in the pool
+    pub fn pending_outflow(&self, address: &[u8]) -> u64 {
+        self.pending.get(address).copied().unwrap_or(0)
+    }
+
+    pub fn pending_outflows(&self) -> &HashMap<Vec<u8>, u64> {
+        &self.pending
+    }
+
+    // Nonce the next transaction of `address` needs, given the next nonce `confirmed` by
+    // the chain. Pending transactions only count while their nonces follow on without a gap.
+    pub fn next_nonce(&self, address: &[u8], confirmed: u64) -> u64 {
+        let mut next = confirmed;
+        if let Some(nonces) = self.pending_nonces.get(address) {
+            while nonces.contains(&next) {
+                next += 1;
+            }
+        }
+        next
+    }
+
+    // Whether an account transaction from `address` with `nonce` is already waiting
+    pub fn has_nonce(&self, address: &[u8], nonce: u64) -> bool {
+        self.pending_nonces
+            .get(address)
+            .is_some_and(|nonces| nonces.contains(&nonce))
+    }
+
+    // ID of the transaction in the pool that spends `outpoint`
+    pub fn spender_of(&self, outpoint: &OutPoint) -> Option<&[u8]> {
+        self.spends.get(outpoint).map(|txid| txid.as_slice())
+    }
+
+    // Every entry, in the order they arrived
+    pub fn entries(&self) -> Vec<&MempoolEntry> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| entry.sequence);
+        entries
+    }
+
+    // Adds the serialized `transaction` paying `fee`, evicting cheaper transactions if the
+    // pool is full. Returns the IDs of the evicted transactions.
+    pub fn insert(
+        &mut self,
+        transaction: Vec<u8>,
+        fee: u64,
+        outflows: Vec<(Vec<u8>, u64)>,
+    ) -> Result<Vec<Vec<u8>>, MempoolRejection> {
+        self.insert_at(transaction, fee, outflows, Instant::now())
+    }
+
+    // Same as `insert` for a transaction that first arrived at `added`, so that putting it
+    // back after a block is disconnected does not restart its expiry
+    pub fn insert_at(
+        &mut self,
+        transaction: Vec<u8>,
+        fee: u64,
+        outflows: Vec<(Vec<u8>, u64)>,
+        added: Instant,
+    ) -> Result<Vec<Vec<u8>>, MempoolRejection> {
+        let txid = Transaction::id_of(&transaction);
+        if self.entries.contains_key(&txid) {
+            return Err(MempoolRejection::Duplicate);
+        }
+        let required = (transaction.len() as u64 * self.min_fee_rate).div_ceil(1000);
+        if fee < required {
+            return Err(MempoolRejection::FeeTooLow { fee, required });
+        }
+        if transaction.len() > self.max_bytes {
+            return Err(MempoolRejection::TooLarge {
+                size: transaction.len(),
+                limit: self.max_bytes,
+            });
+        }
+        let entry = MempoolEntry {
+            transaction,
+            txid: txid.clone(),
+            fee,
+            outflows,
+            added,
+            sequence: self.next_sequence,
+        };
+        // 1. Work out which transactions have to go, cheapest first, before touching the pool
+        let mut count = self.entries.len();
+        let mut bytes = self.bytes;
+        let mut evicted = Vec::<Vec<u8>>::new();
+        let mut cheapest: Vec<&MempoolEntry> = Vec::new();
+        if count >= self.max_count || bytes + entry.size() > self.max_bytes {
+            cheapest = self.entries.values().collect();
+            cheapest.sort_by(|a, b| a.cmp_fee_rate(b).then(a.sequence.cmp(&b.sequence)));
+        }
+        for victim in cheapest {
+            if count < self.max_count && bytes + entry.size() <= self.max_bytes {
+                break;
+            }
+            if victim.cmp_fee_rate(&entry) != Ordering::Less {
+                return Err(MempoolRejection::PoolFull);
+            }
+            count -= 1;
+            bytes -= victim.size();
+            evicted.push(victim.txid.clone());
+        }
+        if count >= self.max_count || bytes + entry.size() > self.max_bytes {
+            return Err(MempoolRejection::PoolFull);
+        }
+        // 2. Then make room and add it
+        for txid in evicted.iter() {
+            self.remove(txid);
+        }
+        self.next_sequence += 1;
+        self.bytes += entry.size();
+        for (address, value) in entry.outflows.iter() {
+            let pending = self.pending.entry(address.clone()).or_insert(0);
+            *pending = pending.saturating_add(*value);
+        }
+        match BlockTransaction::decode(&entry.transaction) {
+            BlockTransaction::Account(tx) => {
+                self.pending_nonces
+                    .entry(tx.sender_address)
+                    .or_default()
+                    .insert(tx.nonce);
+            }
+            BlockTransaction::Utxo(tx) => {
+                for input in tx.inputs {
+                    self.spends.insert(input.previous_output, txid.clone());
+                }
+            }
+        }
+        self.by_age
+            .insert((entry.added, entry.sequence), txid.clone());
+        self.entries.insert(txid, entry);
+        Ok(evicted)
+    }
+
+    pub fn remove(&mut self, txid: &[u8]) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(txid)?;
+        self.by_age.remove(&(entry.added, entry.sequence));
+        self.bytes -= entry.size();
+        for (address, value) in entry.outflows.iter() {
+            if let Some(pending) = self.pending.get_mut(address) {
+                *pending = pending.saturating_sub(*value);
+                if *pending == 0 {
+                    self.pending.remove(address);
+                }
+            }
+        }
+        match BlockTransaction::decode(&entry.transaction) {
+            BlockTransaction::Account(tx) => {
+                if let Some(nonces) = self.pending_nonces.get_mut(&tx.sender_address) {
+                    nonces.remove(&tx.nonce);
+                    if nonces.is_empty() {
+                        self.pending_nonces.remove(&tx.sender_address);
+                    }
+                }
+            }
+            BlockTransaction::Utxo(tx) => {
+                for input in tx.inputs.iter() {
+                    if self.spends.get(&input.previous_output) == Some(&entry.txid) {
+                        self.spends.remove(&input.previous_output);
+                    }
+                }
+            }
+        }
+        Some(entry)
+    }
+
+    // Drops every transaction that has waited longer than the expiry and returns how many
+    pub fn expire(&mut self) -> usize {
+        let mut expired = 0;
+        while let Some((&(added, _), txid)) = self.by_age.first_key_value() {
+            if added.elapsed() <= self.expiry {
+                break;
+            }
+            let txid = txid.clone();
+            self.remove(&txid);
+            expired += 1;
+        }
+        expired
+    }
+
+    pub fn stats(&self) -> MempoolStats {
+        MempoolStats {
+            count: self.entries.len(),
+            bytes: self.bytes,
+            oldest: self
+                .by_age
+                .first_key_value()
+                .map(|(&(added, _), _)| added.elapsed()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block_and_blockchain::Serialization;
+    use crate::blockchain::utxo::{TxInput, TxOutput, UtxoTransaction};
+
+    const EXPIRY: Duration = Duration::from_secs(60);
+
+    // A transaction spending output `n`, all of them the same size
+    fn transaction(n: u8) -> Vec<u8> {
+        UtxoTransaction::new(
+            vec![TxInput::new(OutPoint::new(vec![n], 0))],
+            vec![TxOutput::new(1, b"alice".to_vec())],
+        )
+        .serialization()
+    }
+
+    fn insert(pool: &mut Mempool, n: u8, fee: u64) -> Result<Vec<Vec<u8>>, MempoolRejection> {
+        pool.insert(transaction(n), fee, vec![(b"alice".to_vec(), fee + 1)])
+    }
+
+    #[test]
+    fn duplicates_are_rejected() {
+        let mut pool = Mempool::new(10, 10_000, EXPIRY, 1);
+        insert(&mut pool, 1, 1).unwrap();
+        assert_eq!(insert(&mut pool, 1, 1), Err(MempoolRejection::Duplicate));
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.pending_outflow(b"alice"), 2);
+    }
+
+    #[test]
+    fn a_full_pool_evicts_the_cheapest_by_count() {
+        let mut pool = Mempool::new(2, 10_000, EXPIRY, 1);
+        insert(&mut pool, 1, 1).unwrap();
+        insert(&mut pool, 2, 2).unwrap();
+        let evicted = insert(&mut pool, 3, 3).unwrap();
+        assert_eq!(evicted, vec![Transaction::id_of(&transaction(1))]);
+        assert_eq!(insert(&mut pool, 4, 1), Err(MempoolRejection::PoolFull));
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.pending_outflow(b"alice"), 3 + 4);
+        // The evicted transaction no longer holds on to its output
+        assert_eq!(pool.spender_of(&OutPoint::new(vec![1], 0)), None);
+    }
+
+    #[test]
+    fn a_full_pool_evicts_the_cheapest_by_bytes() {
+        let size = transaction(1).len();
+        let mut pool = Mempool::new(10, 2 * size, EXPIRY, 1);
+        insert(&mut pool, 1, 2).unwrap();
+        insert(&mut pool, 2, 1).unwrap();
+        let evicted = insert(&mut pool, 3, 3).unwrap();
+        assert_eq!(evicted, vec![Transaction::id_of(&transaction(2))]);
+        assert_eq!(pool.bytes(), 2 * size);
+        assert_eq!(insert(&mut pool, 4, 1), Err(MempoolRejection::PoolFull));
+
+        let mut small = Mempool::new(10, size - 1, EXPIRY, 1);
+        assert_eq!(
+            insert(&mut small, 1, 1),
+            Err(MempoolRejection::TooLarge {
+                size,
+                limit: size - 1
+            })
+        );
+    }
+
+    #[test]
+    fn expired_transactions_are_dropped() {
+        let mut pool = Mempool::new(10, 10_000, EXPIRY, 1);
+        let old = Instant::now().checked_sub(2 * EXPIRY).unwrap();
+        pool.insert_at(transaction(1), 1, vec![], old).unwrap();
+        insert(&mut pool, 2, 1).unwrap();
+        assert!(pool.stats().oldest.unwrap() > EXPIRY);
+
+        assert_eq!(pool.expire(), 1);
+        assert!(!pool.contains(&Transaction::id_of(&transaction(1))));
+        assert!(pool.contains(&Transaction::id_of(&transaction(2))));
+        assert!(pool.stats().oldest.unwrap() < EXPIRY);
+        assert_eq!(pool.expire(), 0);
+    }
+
+    #[test]
+    fn transactions_below_the_minimum_fee_rate_are_rejected() {
+        let mut pool = Mempool::new(10, 10_000, EXPIRY, 1);
+        assert_eq!(
+            insert(&mut pool, 1, 0),
+            Err(MempoolRejection::FeeTooLow {
+                fee: 0,
+                required: 1
+            })
+        );
+        let size = transaction(1).len() as u64;
+        let mut pricey = Mempool::new(10, 10_000, EXPIRY, 2000);
+        assert_eq!(
+            insert(&mut pricey, 1, 2 * size - 1),
+            Err(MempoolRejection::FeeTooLow {
+                fee: 2 * size - 1,
+                required: 2 * size
+            })
+        );
+        insert(&mut pricey, 1, 2 * size).unwrap();
+    }
+}
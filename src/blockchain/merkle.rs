@@ -0,0 +1,132 @@
+use crate::blockchain::transaction::Transaction;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// The leaves of the tree are the transaction IDs
+pub fn transaction_hash(tx: &[u8]) -> Vec<u8> {
+    Transaction::id_of(tx)
+}
+
+fn hash_pair(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().to_vec()
+}
+
+// Combines every pair of nodes into the level above, an odd last node is paired with itself
+fn next_level(level: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_pair(left, right),
+            [single] => hash_pair(single, single),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Merkle root over the hashes of the given serialized transactions.
+/// A block without transactions has the all zero root.
+pub fn merkle_root(transactions: &[Vec<u8>]) -> Vec<u8> {
+    if transactions.is_empty() {
+        return vec![0_u8; 32];
+    }
+    let mut level: Vec<Vec<u8>> = transactions.iter().map(|tx| transaction_hash(tx)).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.remove(0)
+}
+
+/// Proof that a transaction is included in a block, checkable with only the
+/// block's merkle root.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub tx_hash: Vec<u8>,
+    // Position of the transaction in the block, tells on which side each sibling goes
+    pub index: usize,
+    // Sibling hashes from the leaf level up to just below the root
+    pub siblings: Vec<Vec<u8>>,
+}
+
+impl MerkleProof {
+    pub fn new(transactions: &[Vec<u8>], index: usize) -> Option<MerkleProof> {
+        if index >= transactions.len() {
+            return None;
+        }
+        let mut level: Vec<Vec<u8>> = transactions.iter().map(|tx| transaction_hash(tx)).collect();
+        let tx_hash = level[index].clone();
+        let mut siblings = Vec::<Vec<u8>>::new();
+        let mut pos = index;
+        while level.len() > 1 {
+            // The last node of an odd level is its own sibling
+            let sibling = if pos.is_multiple_of(2) {
+                level.get(pos + 1).unwrap_or(&level[pos])
+            } else {
+                &level[pos - 1]
+            };
+            siblings.push(sibling.clone());
+            level = next_level(&level);
+            pos /= 2;
+        }
+        Some(MerkleProof {
+            tx_hash,
+            index,
+            siblings,
+        })
+    }
+
+    pub fn verify(&self, merkle_root: &[u8]) -> bool {
+        let mut hash = self.tx_hash.clone();
+        let mut pos = self.index;
+        for sibling in self.siblings.iter() {
+            hash = if pos.is_multiple_of(2) {
+                hash_pair(&hash, sibling)
+            } else {
+                hash_pair(sibling, &hash)
+            };
+            pos /= 2;
+        }
+        // Any bit left over means the index does not fit the tree the siblings describe
+        pos == 0 && hash == merkle_root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transactions(count: usize) -> Vec<Vec<u8>> {
+        (0..count).map(|i| vec![i as u8; 10]).collect()
+    }
+
+    #[test]
+    fn proofs_verify_for_every_transaction() {
+        for count in 1..=9 {
+            let transactions = transactions(count);
+            let root = merkle_root(&transactions);
+            for index in 0..count {
+                let proof = MerkleProof::new(&transactions, index).unwrap();
+                assert!(proof.verify(&root), "{} of {}", index, count);
+            }
+        }
+    }
+
+    #[test]
+    fn proofs_fail_against_another_root_or_index() {
+        let transactions = transactions(5);
+        let root = merkle_root(&transactions);
+        let mut proof = MerkleProof::new(&transactions, 2).unwrap();
+        assert!(!proof.verify(&merkle_root(&transactions[..4])));
+        proof.index = 3;
+        assert!(!proof.verify(&root));
+        proof.index = 2 + (1 << proof.siblings.len());
+        assert!(!proof.verify(&root));
+    }
+
+    #[test]
+    fn no_proof_past_the_last_transaction() {
+        assert_eq!(MerkleProof::new(&transactions(3), 3), None);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::blockchain::block_and_blockchain::BlockHeader;
+use crate::blockchain::target::Target;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Lets another thread stop a running proof of work search, for example when a new tip
+/// arrives or the server shuts down.
+#[derive(Debug, Clone, Default)]
+pub struct MinerHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl MinerHandle {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MiningOutcome {
+    Found { nonce: i32, hash: Vec<u8> },
+    Cancelled,
+    // Every nonce was tried without meeting the target, the header has to change
+    Exhausted,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MiningStats {
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningStats {
+    // Hashes per second over the whole search
+    pub fn hashrate(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        self.hashes as f64 / seconds
+    }
+}
+
+/// Proof of work search that splits the nonce space across worker threads. Worker `i`
+/// of `n` tries the nonces `start + i`, `start + i + n`, `start + i + 2n`, ...
+#[derive(Debug, Clone)]
+pub struct Miner {
+    threads: usize,
+    handle: MinerHandle,
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Self {
+        Miner {
+            threads: threads.max(1),
+            handle: MinerHandle::default(),
+        }
+    }
+
+    // One worker per available CPU
+    pub fn with_available_parallelism() -> Self {
+        Miner::new(thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    // Keeps the handle, so whoever holds it can still cancel
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    pub fn handle(&self) -> MinerHandle {
+        self.handle.clone()
+    }
+
+    // Clears a cancel meant for the block mined before, once the one replacing it is
+    // assembled. A cancel issued after that stops its search, even before it starts.
+    pub fn begin_block(&self) {
+        self.handle.reset();
+    }
+
+    // Searches for a nonce that makes `header` meet its own target, unless the handle
+    // cancels it
+    pub fn mine(&self, header: &BlockHeader) -> (MiningOutcome, MiningStats) {
+        let start = Instant::now();
+        let target = Target::from_compact(header.bits()).unwrap_or(Target::MAX);
+        let hashes = AtomicU64::new(0);
+        let found = AtomicBool::new(false);
+        let step = self.threads as i32;
+        // Every worker covers its share of the 2^32 possible nonces exactly once
+        let per_thread = (1_u64 << 32).div_ceil(self.threads as u64);
+
+        let winner = thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.threads)
+                .map(|i| {
+                    let mut candidate = header.clone();
+                    candidate.set_nonce(header.nonce().wrapping_add(i as i32));
+                    let (found, hashes, handle) = (&found, &hashes, &self.handle);
+                    scope.spawn(move || {
+                        let mut tried = 0_u64;
+                        let mut result = None;
+                        while tried < per_thread {
+                            if found.load(Ordering::Relaxed) || handle.is_cancelled() {
+                                break;
+                            }
+                            let hash = candidate.hash_bytes();
+                            tried += 1;
+                            if target.is_met_by(&hash) {
+                                found.store(true, Ordering::Relaxed);
+                                result = Some((candidate.nonce(), hash.to_vec()));
+                                break;
+                            }
+                            candidate.set_nonce(candidate.nonce().wrapping_add(step));
+                        }
+                        hashes.fetch_add(tried, Ordering::Relaxed);
+                        result
+                    })
+                })
+                .collect();
+            workers.into_iter().fold(None, |winner, worker| {
+                let result = worker.join().expect("Mining thread panicked");
+                winner.or(result)
+            })
+        });
+
+        let stats = MiningStats {
+            hashes: hashes.load(Ordering::Relaxed),
+            elapsed: start.elapsed(),
+        };
+        let outcome = match winner {
+            Some((nonce, hash)) => MiningOutcome::Found { nonce, hash },
+            None if self.handle.is_cancelled() => MiningOutcome::Cancelled,
+            None => MiningOutcome::Exhausted,
+        };
+        (outcome, stats)
+    }
+}
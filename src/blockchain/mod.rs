@@ -1,2 +1,15 @@
 pub mod block_and_blockchain;
+pub mod block_index;
+pub mod block_stream;
+pub mod block_tree;
+pub mod chain_params;
+pub mod chain_state;
+pub mod encoding;
+pub mod mempool;
+pub mod merkle;
+pub mod miner;
+pub mod storage;
+pub mod target;
 pub mod transaction;
+pub mod utxo;
+pub mod validation;
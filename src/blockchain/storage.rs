@@ -0,0 +1,293 @@
+use crate::blockchain::block_and_blockchain::{Block, Serialization};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Append-only on-disk block store.
+///
+/// Blocks are written one after the other into `blocks.dat` in their binary serialization, and `blocks.idx` holds one
+/// fixed size entry (offset and length, both big endian u64) per block. A block is only
+/// considered stored once its index entry is written, so a crash between the two writes
+/// leaves trailing bytes that are dropped the next time the store is opened.
+#[derive(Debug, Clone)]
+pub struct BlockStore {
+    dir: PathBuf,
+}
+
+impl BlockStore {
+    const DATA_FILE: &str = "blocks.dat";
+    const INDEX_FILE: &str = "blocks.idx";
+    const INDEX_ENTRY_SIZE: u64 = 16;
+
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        let store = BlockStore {
+            dir: dir.as_ref().to_path_buf(),
+        };
+        store.recover()?;
+        Ok(store)
+    }
+
+    fn data_path(&self) -> PathBuf {
+        self.dir.join(BlockStore::DATA_FILE)
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.dir.join(BlockStore::INDEX_FILE)
+    }
+
+    fn open_file(path: &Path) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+    }
+
+    // Cuts both files back to the last block that was completely written
+    fn recover(&self) -> io::Result<()> {
+        let data = BlockStore::open_file(&self.data_path())?;
+        let index = BlockStore::open_file(&self.index_path())?;
+        let data_len = data.metadata()?.len();
+        // 1. Drop a partially written index entry
+        let mut entries = self.read_index()?;
+        // 2. Drop entries pointing past the end of the data file or out of order
+        let mut end = 0;
+        let mut valid = 0;
+        for (offset, length) in entries.iter() {
+            if *offset != end || offset + length > data_len {
+                break;
+            }
+            end = offset + length;
+            valid += 1;
+        }
+        // 3. The last block may have hit the disk only partly even though its entry did not
+        if valid > 0 {
+            let (offset, length) = entries[valid - 1];
+            if self.read_block(offset, length).is_err() {
+                valid -= 1;
+                end = offset;
+            }
+        }
+        if valid < entries.len() {
+            println!(
+                "Block store: dropping {} incomplete block(s)",
+                entries.len() - valid
+            );
+        }
+        entries.truncate(valid);
+        index.set_len(valid as u64 * BlockStore::INDEX_ENTRY_SIZE)?;
+        // 4. Anything after the last indexed block is a half-written block
+        if data_len > end {
+            println!(
+                "Block store: truncating {} trailing byte(s)",
+                data_len - end
+            );
+        }
+        data.set_len(end)?;
+        data.sync_all()?;
+        index.sync_all()?;
+        Ok(())
+    }
+
+    fn read_index(&self) -> io::Result<Vec<(u64, u64)>> {
+        let mut bytes = Vec::<u8>::new();
+        File::open(self.index_path())?.read_to_end(&mut bytes)?;
+        let entries = bytes
+            .chunks_exact(BlockStore::INDEX_ENTRY_SIZE as usize)
+            .map(|entry| {
+                let offset = u64::from_be_bytes(entry[0..8].try_into().unwrap());
+                let length = u64::from_be_bytes(entry[8..16].try_into().unwrap());
+                (offset, length)
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    fn read_block(&self, offset: u64, length: u64) -> io::Result<Block> {
+        let mut bytes = Vec::<u8>::new();
+        File::open(self.data_path())?.read_to_end(&mut bytes)?;
+        BlockStore::decode(&bytes[offset as usize..(offset + length) as usize])
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Block> {
+        match bytes.first() {
+            Some(&Block::FORMAT_VERSION) => Block::deserialization(bytes.to_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+            // Stores written before the binary format hold JSON blocks
+            Some(b'{') => serde_json::from_slice(bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unsupported block format",
+            )),
+        }
+    }
+
+    pub fn append(&self, block: &Block) -> io::Result<()> {
+        let bytes = block.serialization();
+        // 1. Write the block itself and make sure it is on disk. It goes right after the
+        // last indexed block, over whatever a failed append left behind.
+        let offset = self
+            .read_index()?
+            .last()
+            .map_or(0, |(offset, length)| offset + length);
+        let mut data = BlockStore::open_file(&self.data_path())?;
+        data.set_len(offset)?;
+        data.write_all(&bytes)?;
+        data.sync_all()?;
+        // 2. Only then publish it through the index
+        let mut index = BlockStore::open_file(&self.index_path())?;
+        let mut entry = Vec::<u8>::with_capacity(BlockStore::INDEX_ENTRY_SIZE as usize);
+        entry.extend(offset.to_be_bytes());
+        entry.extend((bytes.len() as u64).to_be_bytes());
+        index.write_all(&entry)?;
+        index.sync_all()
+    }
+
+    // Drops every block from `height` on, used when the active chain is reorganized
+    pub fn truncate(&self, height: usize) -> io::Result<()> {
+        let entries = self.read_index()?;
+        if height >= entries.len() {
+            return Ok(());
+        }
+        // Shrink the index first so a crash never leaves an entry without its block
+        let index = BlockStore::open_file(&self.index_path())?;
+        index.set_len(height as u64 * BlockStore::INDEX_ENTRY_SIZE)?;
+        index.sync_all()?;
+        let data = BlockStore::open_file(&self.data_path())?;
+        data.set_len(entries[height].0)?;
+        data.sync_all()
+    }
+
+    pub fn load_blocks(&self) -> io::Result<Vec<Block>> {
+        let mut data = Vec::<u8>::new();
+        File::open(self.data_path())?.read_to_end(&mut data)?;
+        let mut blocks = Vec::<Block>::new();
+        for (offset, length) in self.read_index()? {
+            let start = offset as usize;
+            let end = start + length as usize;
+            if end > data.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "block store index points past the end of the data file",
+                ));
+            }
+            blocks.push(BlockStore::decode(&data[start..end])?);
+        }
+        Ok(blocks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::chain_params::ChainParams;
+
+    // A fresh directory for each test, removed again when it is dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("store-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn blocks(count: usize) -> Vec<Block> {
+        let mut blocks = vec![Block::genesis(&ChainParams::regtest())];
+        while blocks.len() < count {
+            let previous = blocks.last().unwrap().hash();
+            blocks.push(Block::new(blocks.len() as i32, previous, 0x2000_ffff));
+        }
+        blocks
+    }
+
+    fn file_len(path: &Path) -> u64 {
+        fs::metadata(path).unwrap().len()
+    }
+
+    #[test]
+    fn blocks_survive_a_reopen() {
+        let dir = TempDir::new("reopen");
+        let blocks = blocks(3);
+        let store = BlockStore::open(&dir.0).unwrap();
+        for block in blocks.iter() {
+            store.append(block).unwrap();
+        }
+        let store = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(store.load_blocks().unwrap(), blocks);
+
+        store.truncate(1).unwrap();
+        assert_eq!(store.load_blocks().unwrap(), blocks[..1]);
+        store.append(&blocks[1]).unwrap();
+        assert_eq!(store.load_blocks().unwrap(), blocks[..2]);
+    }
+
+    #[test]
+    fn a_torn_block_is_dropped_on_open() {
+        let dir = TempDir::new("torn-block");
+        let blocks = blocks(3);
+        let store = BlockStore::open(&dir.0).unwrap();
+        for block in blocks.iter() {
+            store.append(block).unwrap();
+        }
+        // The last block only partly made it to disk
+        let data = OpenOptions::new()
+            .write(true)
+            .open(store.data_path())
+            .unwrap();
+        data.set_len(file_len(&store.data_path()) - 3).unwrap();
+
+        let store = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(store.load_blocks().unwrap(), blocks[..2]);
+        assert_eq!(
+            file_len(&store.index_path()),
+            2 * BlockStore::INDEX_ENTRY_SIZE
+        );
+        store.append(&blocks[2]).unwrap();
+        assert_eq!(store.load_blocks().unwrap(), blocks);
+    }
+
+    #[test]
+    fn an_unindexed_tail_is_dropped_on_open() {
+        let dir = TempDir::new("unindexed-tail");
+        let blocks = blocks(2);
+        let store = BlockStore::open(&dir.0).unwrap();
+        store.append(&blocks[0]).unwrap();
+        let end = file_len(&store.data_path());
+        // A block was written but its index entry only partly
+        let mut data = BlockStore::open_file(&store.data_path()).unwrap();
+        data.write_all(&blocks[1].serialization()).unwrap();
+        let mut index = BlockStore::open_file(&store.index_path()).unwrap();
+        index.write_all(&[0; 5]).unwrap();
+
+        let store = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(store.load_blocks().unwrap(), blocks[..1]);
+        assert_eq!(file_len(&store.data_path()), end);
+        assert_eq!(file_len(&store.index_path()), BlockStore::INDEX_ENTRY_SIZE);
+    }
+
+    #[test]
+    fn an_append_writes_over_what_a_failed_one_left() {
+        let dir = TempDir::new("failed-append");
+        let blocks = blocks(2);
+        let store = BlockStore::open(&dir.0).unwrap();
+        store.append(&blocks[0]).unwrap();
+        // The data of a block hit the disk, then writing its index entry failed
+        let mut data = BlockStore::open_file(&store.data_path()).unwrap();
+        data.write_all(&[0xAA; 7]).unwrap();
+
+        store.append(&blocks[1]).unwrap();
+        assert_eq!(store.load_blocks().unwrap(), blocks);
+        let store = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(store.load_blocks().unwrap(), blocks);
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt::Display;
+
+/// A 256-bit proof-of-work target stored as big-endian bytes, so it can be
+/// compared directly against the raw output of `Block::hash()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Target([u8; 32]);
+
+impl Target {
+    pub const MAX: Target = Target([0xff; 32]);
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Target(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    // Decodes the Bitcoin style compact "bits" encoding: one byte of size followed by a
+    // three byte mantissa, target = mantissa * 256^(size - 3)
+    pub fn from_compact(bits: u32) -> Option<Target> {
+        let size = (bits >> 24) as usize;
+        let mut mantissa = bits & 0x007f_ffff;
+        // The sign bit is never set by a valid encoding
+        if bits & 0x0080_0000 != 0 {
+            return None;
+        }
+        let mut bytes = [0_u8; 32];
+        if size <= 3 {
+            mantissa >>= 8 * (3 - size);
+            bytes[28..32].copy_from_slice(&mantissa.to_be_bytes());
+        } else {
+            // The mantissa must fit in the 32 bytes of the target
+            for (i, byte) in mantissa.to_be_bytes()[1..4].iter().enumerate() {
+                match (32 + i).checked_sub(size) {
+                    Some(pos) => bytes[pos] = *byte,
+                    None if *byte != 0 => return None,
+                    None => {}
+                }
+            }
+        }
+        Some(Target(bytes))
+    }
+
+    pub fn to_compact(self) -> u32 {
+        // 1. Number of significant bytes in the target
+        let mut size = 32 - self.0.iter().take_while(|b| **b == 0).count();
+        // 2. The three most significant bytes become the mantissa
+        let mut mantissa: u32 = 0;
+        for i in 0..3 {
+            mantissa <<= 8;
+            if i < size {
+                mantissa |= self.0[32 - size + i] as u32;
+            }
+        }
+        // 3. Keep the sign bit clear by moving to a bigger size
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+        ((size as u32) << 24) | mantissa
+    }
+
+    pub fn is_met_by(&self, hash: &[u8]) -> bool {
+        hash.len() == 32 && hash <= &self.0[..]
+    }
+
+    // Scales the target by numerator / denominator, saturating at Target::MAX
+    pub fn mul_div(&self, numerator: u64, denominator: u64) -> Target {
+        // The product of a 256-bit and a 64-bit number always fits in 320 bits
+        let mut wide = [0_u8; 40];
+        wide[8..].copy_from_slice(&self.0);
+        // 1. Multiplication, from the least significant byte upwards
+        let mut carry: u128 = 0;
+        for byte in wide.iter_mut().rev() {
+            let acc = *byte as u128 * numerator as u128 + carry;
+            *byte = (acc & 0xff) as u8;
+            carry = acc >> 8;
+        }
+        // 2. Long division, from the most significant byte downwards
+        let mut remainder: u128 = 0;
+        for byte in wide.iter_mut() {
+            remainder = (remainder << 8) | *byte as u128;
+            *byte = (remainder / denominator as u128) as u8;
+            remainder %= denominator as u128;
+        }
+        if wide[..8].iter().any(|b| *b != 0) {
+            return Target::MAX;
+        }
+        let mut bytes = [0_u8; 32];
+        bytes.copy_from_slice(&wide[8..]);
+        Target(bytes)
+    }
+
+    // Expected number of hashes needed to meet the target, 2^256 / (target + 1).
+    // Only the top 64 bits of the target are used, which is plenty to compare
+    // the cumulative work of two branches. Saturates for targets below 2^128.
+    pub fn work(&self) -> u128 {
+        let leading_zeros = self.0.iter().take_while(|b| **b == 0).count();
+        // 1. Bit length of the target and its 64 most significant bits
+        let bit_len = (32 - leading_zeros) * 8;
+        if bit_len <= 128 {
+            return u128::MAX;
+        }
+        let mut top = [0_u8; 8];
+        top.copy_from_slice(&self.0[leading_zeros..leading_zeros + 8]);
+        // The dropped low bits and the + 1 only matter past the 64th significant bit
+        let divisor = u64::from_be_bytes(top) as u128 + 1;
+        // 2. target ~ divisor * 2^shift, so work ~ 2^(256 - shift) / divisor
+        let shift = bit_len - 64;
+        let exponent = 256 - shift;
+        if exponent <= 127 {
+            return (1_u128 << exponent) / divisor;
+        }
+        let quotient = (1_u128 << 127) / divisor;
+        quotient
+            .checked_shl((exponent - 127) as u32)
+            .filter(|work| work >> (exponent - 127) == quotient)
+            .unwrap_or(u128::MAX)
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_round_trip() {
+        for bits in [
+            0x1d00ffff, 0x1f00ffff, 0x207fffff, 0x1b0404cb, 0x03123456, 0x04123456,
+        ] {
+            let target = Target::from_compact(bits).unwrap();
+            assert_eq!(target.to_compact(), bits);
+        }
+    }
+
+    #[test]
+    fn compact_of_a_target_round_trips() {
+        let mut bytes = [0_u8; 32];
+        bytes[4] = 0x80;
+        // A mantissa with its top bit set moves to a bigger size
+        let target = Target::from_bytes(bytes);
+        let bits = target.to_compact();
+        assert_eq!(bits & 0x0080_0000, 0);
+        assert_eq!(Target::from_compact(bits), Some(target));
+    }
+
+    #[test]
+    fn negative_and_oversized_compacts_are_refused() {
+        assert_eq!(Target::from_compact(0x04923456), None);
+        assert_eq!(Target::from_compact(0x23123456), None);
+    }
+}
@@ -1,87 +1,350 @@
 use crate::blockchain::block_and_blockchain::Serialization;
+use crate::blockchain::encoding::{
+    finish, read_bytes, read_u64, read_var_bytes, write_bytes, write_var_bytes, DecodeError,
+    MAX_FIELD_LENGTH,
+};
+use crate::blockchain::utxo::UtxoTransaction;
+use crate::wallet::wallet::Wallet;
+use sha2::{Digest, Sha256};
 use std::fmt::Display;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Transaction {
     pub sender_address: Vec<u8>,
     pub recipient_address: Vec<u8>,
     pub value: u64,
+    // Paid to the miner on top of `value`
+    pub fee: u64,
+    // Number of account transactions the sender made before this one
+    pub nonce: u64,
+    // Key of the sender as x || y, whose address has to be `sender_address`
+    pub public_key: Vec<u8>,
+    // Signature of `signing_bytes` with that key. Both are empty in the legacy layout.
+    pub signature: Vec<u8>,
 }
 
 impl Transaction {
-    pub fn new(sender: Vec<u8>, recipient: Vec<u8>, value: u64) -> Transaction {
+    // First byte of a serialized account transaction. Transactions written before there was
+    // a version start with 0x00 instead, and UTXO transactions with `UtxoTransaction::TAG`.
+    pub const FORMAT_VERSION: u8 = 0x01;
+
+    pub fn new(
+        sender: Vec<u8>,
+        recipient: Vec<u8>,
+        value: u64,
+        fee: u64,
+        nonce: u64,
+    ) -> Transaction {
         Transaction {
             sender_address: sender,
             recipient_address: recipient,
             value,
+            fee,
+            nonce,
+            public_key: Vec::new(),
+            signature: Vec::new(),
         }
     }
+
+    // Transactions of the legacy layout carry no key, the versioned one always does
+    pub fn is_legacy(&self) -> bool {
+        self.public_key.is_empty()
+    }
+
+    // What the sender signs: the whole transaction with the signature left out
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut unsigned = self.clone();
+        unsigned.signature.clear();
+        unsigned.serialization()
+    }
+
+    // Checks the key belongs to the sender and signed the transaction
+    pub fn verify(&self, address_version: u8) -> bool {
+        let address = Wallet::address_from_public_key(&self.public_key, address_version);
+        address.as_bytes() == self.sender_address
+            && Wallet::verify_signature(&self.public_key, &self.signing_bytes(), &self.signature)
+    }
+
+    // Stable transaction ID, the hash of the canonical serialization
+    pub fn id(&self) -> Vec<u8> {
+        Transaction::id_of(&self.serialization())
+    }
+
+    pub fn id_of(serialized: &[u8]) -> Vec<u8> {
+        Sha256::digest(serialized).to_vec()
+    }
+}
+
+/// Either kind of transaction a block can carry, told apart by the first serialized byte.
+#[derive(Debug)]
+pub enum BlockTransaction {
+    Account(Transaction),
+    Utxo(UtxoTransaction),
+}
+
+impl BlockTransaction {
+    // Decodes a transaction of a new block or for the pool, in the current layouts only
+    pub fn try_decode(serialized: &[u8]) -> Result<BlockTransaction, DecodeError> {
+        if UtxoTransaction::is_utxo(serialized) {
+            Ok(BlockTransaction::Utxo(UtxoTransaction::deserialization(
+                serialized.to_vec(),
+            )?))
+        } else {
+            Ok(BlockTransaction::Account(Transaction::deserialization(
+                serialized.to_vec(),
+            )?))
+        }
+    }
+
+    // Same as `try_decode` for a block read back from the block store, where account
+    // transactions may still use the legacy layout. They decode so a store holding them is
+    // turned down for what they are: transactions nobody signed.
+    pub fn try_decode_stored(serialized: &[u8]) -> Result<BlockTransaction, DecodeError> {
+        if UtxoTransaction::is_utxo(serialized) {
+            BlockTransaction::try_decode(serialized)
+        } else {
+            Ok(BlockTransaction::Account(
+                Transaction::deserialization_stored(serialized.to_vec())?,
+            ))
+        }
+    }
+
+    // Only for transactions that already went through `try_decode` or `try_decode_stored`,
+    // like the ones of a validated block or of the pool
+    pub fn decode(serialized: &[u8]) -> BlockTransaction {
+        BlockTransaction::try_decode_stored(serialized)
+            .expect("Error decoding a checked transaction")
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        match self {
+            BlockTransaction::Account(_) => false,
+            BlockTransaction::Utxo(tx) => tx.is_coinbase(),
+        }
+    }
+}
+
+impl Display for BlockTransaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockTransaction::Account(tx) => tx.fmt(f),
+            BlockTransaction::Utxo(tx) => tx.fmt(f),
+        }
+    }
+}
+
+/// A confirmed transaction together with where it sits in the active chain.
+#[derive(Debug)]
+pub struct TransactionLookup {
+    pub transaction: BlockTransaction,
+    pub block_height: usize,
+    pub block_hash: Vec<u8>,
+    // 1 when the transaction is in the tip block
+    pub confirmations: usize,
 }
 
 impl Serialization<Transaction> for Transaction {
     fn serialization(&self) -> Vec<u8> {
+        // A legacy transaction keeps its layout, or its ID would change
+        if self.is_legacy() {
+            return self.legacy_serialization();
+        }
+        // 1. The format version, so the layout can change without breaking old blocks
+        let mut bin = vec![Transaction::FORMAT_VERSION];
+        // 2. Both addresses behind their varint lengths, one byte for any real address
+        write_var_bytes(&mut bin, &self.sender_address);
+        write_var_bytes(&mut bin, &self.recipient_address);
+        // 3. The value, the fee and the nonce, fixed size so they need no length
+        bin.extend(self.value.to_be_bytes());
+        bin.extend(self.fee.to_be_bytes());
+        bin.extend(self.nonce.to_be_bytes());
+        // 4. The sender's key and signature
+        write_var_bytes(&mut bin, &self.public_key);
+        write_var_bytes(&mut bin, &self.signature);
+        bin
+    }
+
+    fn deserialization(bytes: Vec<u8>) -> Result<Transaction, DecodeError> {
+        match bytes.first() {
+            Some(&Transaction::FORMAT_VERSION) => Transaction::read(&bytes),
+            Some(0) => Err(DecodeError::Invalid(
+                "the legacy layout is only read from the block store",
+            )),
+            Some(version) => Err(DecodeError::UnsupportedVersion(*version)),
+            None => Err(DecodeError::UnexpectedEnd),
+        }
+    }
+}
+
+impl Transaction {
+    // Also reads the legacy layout, for blocks read back from the block store
+    pub fn deserialization_stored(bytes: Vec<u8>) -> Result<Transaction, DecodeError> {
+        match bytes.first() {
+            // Lengths of the legacy layout are 8 bytes and far below 2^56
+            Some(0) => Transaction::read_legacy(&bytes),
+            _ => Transaction::deserialization(bytes),
+        }
+    }
+
+    fn read(bytes: &[u8]) -> Result<Transaction, DecodeError> {
+        // Skipping the version
+        let mut pos = 1;
+        let sender_address = read_var_bytes(bytes, &mut pos, MAX_FIELD_LENGTH)?;
+        let recipient_address = read_var_bytes(bytes, &mut pos, MAX_FIELD_LENGTH)?;
+        let value = read_u64(bytes, &mut pos)?;
+        let fee = read_u64(bytes, &mut pos)?;
+        let nonce = read_u64(bytes, &mut pos)?;
+        let public_key = read_var_bytes(bytes, &mut pos, MAX_FIELD_LENGTH)?;
+        let signature = read_var_bytes(bytes, &mut pos, MAX_FIELD_LENGTH)?;
+        finish(bytes, pos)?;
+        // An empty key is how legacy transactions are told apart, so it cannot be allowed here
+        if public_key.is_empty() {
+            return Err(DecodeError::Invalid(
+                "account transactions carry the sender's key",
+            ));
+        }
+
+        Ok(Transaction {
+            sender_address,
+            recipient_address,
+            value,
+            fee,
+            nonce,
+            public_key,
+            signature,
+        })
+    }
+
+    // The layout of blocks mined before the versioned one, where every field, the amounts
+    // included, came behind a 64-bit length. Only kept so that those blocks still load.
+    fn legacy_serialization(&self) -> Vec<u8> {
         let mut bin = Vec::<u8>::new();
-        // 1. Find the length of the sender's address
-        let sender_length = self.sender_address.len();
-        // 2. create a vector for the bytes version of sender_length and concatenate to the bin vector
-        bin.extend(sender_length.to_be_bytes().to_vec());
-        // 3. Concatenate the senders address as it's already in bytes
-        bin.extend(&self.sender_address);
-        // 4. Find the length of the recipient's address
-        let recipient_length = self.recipient_address.len();
-        // 5. create a vector for the bytes version of recipient_length and concatenate to the bin vector
-        bin.extend(recipient_length.to_be_bytes().to_vec());
-        // 6. Concatenate the recipient address as it's already in bytes
-        bin.extend(&self.recipient_address);
-        // 7. Find the length of the value after converting it to bytes
-        let value_length = self.value.to_be_bytes().len();
-        // 8. create a vector for the bytes version of value_length and concatenate to the bin vector
-        bin.extend(value_length.to_be_bytes().to_vec());
-        // 9. Convert the value to bytes and concatenate it to the bin vector
-        bin.extend(self.value.to_be_bytes().to_vec());
+        write_bytes(&mut bin, &self.sender_address);
+        write_bytes(&mut bin, &self.recipient_address);
+        for amount in [self.value, self.fee, self.nonce] {
+            write_bytes(&mut bin, &amount.to_be_bytes());
+        }
         bin
     }
 
-    fn deserialization(bytes: Vec<u8>) -> Transaction {
+    fn read_legacy(bytes: &[u8]) -> Result<Transaction, DecodeError> {
         let mut pos = 0;
-        // 1. Getting the sender's length. In serialization,it was the first element hence it occupied the first 8 bytes
-        // Try into converts the slice into array
-        let sender_length = usize::from_be_bytes(bytes[pos..pos + 8].try_into().unwrap());
-        pos += 8;
-        // 2. Getting the sender's address
-        let mut sender_address = Vec::<u8>::new();
-        sender_address.extend_from_slice(&bytes[pos..pos + sender_length]);
-        pos += sender_length;
-        // 3. Getting the recipient's length
-        let recipient_length = usize::from_be_bytes(bytes[pos..pos + 8].try_into().unwrap());
-        pos += 8;
-        // 4. Getting the recipient's address
-        let mut recipient_address = Vec::<u8>::new();
-        recipient_address.extend_from_slice(&bytes[pos..pos + recipient_length]);
-        pos += recipient_length;
-        // 5. Getting the value's length
-        let value_length = usize::from_be_bytes(bytes[pos..pos + 8].try_into().unwrap());
-        pos += 8;
-        let value: u64 = u64::from_be_bytes(bytes[pos..pos + value_length].try_into().unwrap());
+        // 1. Getting the sender's address, its length came first and occupies 8 bytes
+        let sender_address = read_bytes(bytes, &mut pos, MAX_FIELD_LENGTH)?;
+        // 2. Getting the recipient's address
+        let recipient_address = read_bytes(bytes, &mut pos, MAX_FIELD_LENGTH)?;
+        // 3. Getting the value, the fee and the nonce, each one behind a length of 8
+        let value = read_amount(bytes, &mut pos)?;
+        let fee = read_amount(bytes, &mut pos)?;
+        let nonce = read_amount(bytes, &mut pos)?;
+        // 4. Nothing may follow
+        finish(bytes, pos)?;
 
-        Transaction {
+        Ok(Transaction::new(
             sender_address,
             recipient_address,
             value,
-        }
+            fee,
+            nonce,
+        ))
+    }
+}
+
+// A u64 of the legacy layout, written with its length in front which has to be 8
+fn read_amount(bytes: &[u8], pos: &mut usize) -> Result<u64, DecodeError> {
+    if read_u64(bytes, pos)? != 8 {
+        return Err(DecodeError::Invalid("amounts are 8 bytes long"));
     }
+    read_u64(bytes, pos)
 }
 
 impl Display for Transaction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{:?}\nSender Address: {:?}\nRecipient Address: {:?}\nValue: {:?}\n {}",
+            "{:?}\nSender Address: {:?}\nRecipient Address: {:?}\nValue: {:?}\nFee: {:?}\nNonce: {:?}\n {}",
             "-".repeat(40),
-            String::from_utf8(self.sender_address.clone()).unwrap(),
-            String::from_utf8(self.recipient_address.clone()).unwrap(),
+            String::from_utf8_lossy(&self.sender_address),
+            String::from_utf8_lossy(&self.recipient_address),
             self.value,
+            self.fee,
+            self.nonce,
             "-".repeat(40)
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed() -> Transaction {
+        let mut tx = Transaction::new(b"sender".to_vec(), b"recipient".to_vec(), 5, 1, 7);
+        tx.public_key = vec![4; 64];
+        tx.signature = vec![9; 64];
+        tx
+    }
+
+    #[test]
+    fn round_trip() {
+        let tx = signed();
+        let bytes = tx.serialization();
+        let back = Transaction::deserialization(bytes.clone()).unwrap();
+        assert_eq!(back.serialization(), bytes);
+        assert_eq!(back.id(), Transaction::id_of(&bytes));
+    }
+
+    #[test]
+    fn every_truncation_fails() {
+        let bytes = signed().serialization();
+        for length in 0..bytes.len() {
+            assert!(Transaction::deserialization(bytes[..length].to_vec()).is_err());
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_fail() {
+        let mut bytes = signed().serialization();
+        bytes.push(0);
+        assert_eq!(
+            Transaction::deserialization(bytes).err(),
+            Some(DecodeError::TrailingBytes(1))
+        );
+    }
+
+    #[test]
+    fn oversized_address_fails() {
+        let mut tx = signed();
+        tx.sender_address = vec![b'a'; MAX_FIELD_LENGTH + 1];
+        assert!(matches!(
+            Transaction::deserialization(tx.serialization()),
+            Err(DecodeError::TooLong { .. })
+        ));
+    }
+
+    #[test]
+    fn unknown_version_fails() {
+        let mut bytes = signed().serialization();
+        bytes[0] = 0x7f;
+        assert_eq!(
+            Transaction::deserialization(bytes).err(),
+            Some(DecodeError::UnsupportedVersion(0x7f))
+        );
+    }
+
+    #[test]
+    fn legacy_layout_is_only_read_from_the_store() {
+        let legacy = Transaction::new(b"sender".to_vec(), b"recipient".to_vec(), 5, 1, 7);
+        let bytes = legacy.serialization();
+        assert_eq!(bytes[0], 0);
+        assert!(BlockTransaction::try_decode(&bytes).is_err());
+        let Ok(BlockTransaction::Account(back)) = BlockTransaction::try_decode_stored(&bytes)
+        else {
+            panic!("the legacy layout does not decode from the store");
+        };
+        // Its ID is the one it was stored with
+        assert_eq!(back.id(), Transaction::id_of(&bytes));
+        // Without a key it can never be verified
+        assert!(!back.verify(0x6f));
+    }
+}
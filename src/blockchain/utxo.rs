@@ -0,0 +1,355 @@
+use crate::blockchain::block_and_blockchain::Serialization;
+use crate::blockchain::encoding::{
+    finish, read_bytes, read_u32, read_u64, read_u8, write_bytes, DecodeError, MAX_FIELD_LENGTH,
+};
+use crate::blockchain::transaction::Transaction;
+use crate::wallet::wallet::Wallet;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Display;
+
+/// Points at output number `index` of the transaction with ID `txid`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutPoint {
+    pub txid: Vec<u8>,
+    pub index: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: Vec<u8>, index: u32) -> Self {
+        OutPoint { txid, index }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxInput {
+    pub previous_output: OutPoint,
+    // Uncompressed key without the 0x04 prefix, x || y like `Wallet::public_key_str`
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl TxInput {
+    // An input still waiting for its owner's signature
+    pub fn new(previous_output: OutPoint) -> Self {
+        TxInput {
+            previous_output,
+            public_key: Vec::new(),
+            signature: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxOutput {
+    pub value: u64,
+    pub address: Vec<u8>,
+}
+
+impl TxOutput {
+    pub fn new(value: u64, address: Vec<u8>) -> Self {
+        TxOutput { value, address }
+    }
+}
+
+/// A transaction that spends earlier outputs instead of drawing on an account balance.
+/// Whatever the inputs hold beyond the value of the outputs is left to the miner.
+///
+/// A transaction without inputs is a coinbase, it can only be the first transaction of a
+/// block and creates the block reward.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UtxoTransaction {
+    pub inputs: Vec<TxInput>,
+    pub outputs: Vec<TxOutput>,
+    // Height of the block a coinbase belongs to, so that no two coinbases share an ID.
+    // Always 0 for other transactions.
+    pub coinbase_height: u64,
+}
+
+impl UtxoTransaction {
+    // First byte of every serialized UTXO transaction. An account transaction starts with
+    // `Transaction::FORMAT_VERSION`, or 0x00 in the legacy layout.
+    pub const TAG: u8 = 0xff;
+
+    pub fn new(inputs: Vec<TxInput>, outputs: Vec<TxOutput>) -> Self {
+        UtxoTransaction {
+            inputs,
+            outputs,
+            coinbase_height: 0,
+        }
+    }
+
+    pub fn coinbase(height: u64, outputs: Vec<TxOutput>) -> Self {
+        UtxoTransaction {
+            inputs: Vec::new(),
+            outputs,
+            coinbase_height: height,
+        }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    pub fn is_utxo(serialized: &[u8]) -> bool {
+        serialized.first() == Some(&UtxoTransaction::TAG)
+    }
+
+    pub fn id(&self) -> Vec<u8> {
+        Transaction::id_of(&self.serialization())
+    }
+
+    // Sum of the outputs, None if it does not fit in a u64
+    pub fn output_value(&self) -> Option<u64> {
+        self.outputs
+            .iter()
+            .try_fold(0_u64, |sum, output| sum.checked_add(output.value))
+    }
+
+    // What every input signs: the whole transaction with the signatures left out
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut unsigned = self.clone();
+        for input in unsigned.inputs.iter_mut() {
+            input.signature.clear();
+        }
+        unsigned.serialization()
+    }
+
+    // Checks the signature of input `index` and that its key owns `spent`, the output it
+    // spends, on a network whose addresses start with `address_version`
+    pub fn verify_input(&self, index: usize, spent: &TxOutput, address_version: u8) -> bool {
+        let Some(input) = self.inputs.get(index) else {
+            return false;
+        };
+        let address = Wallet::address_from_public_key(&input.public_key, address_version);
+        if address.as_bytes() != spent.address {
+            return false;
+        }
+        Wallet::verify_signature(&input.public_key, &self.signing_bytes(), &input.signature)
+    }
+}
+
+impl Serialization<UtxoTransaction> for UtxoTransaction {
+    fn serialization(&self) -> Vec<u8> {
+        let mut bin = vec![UtxoTransaction::TAG];
+        // 1. The inputs, each one a reference to an output with the key and signature spending it
+        bin.extend((self.inputs.len() as u32).to_be_bytes());
+        for input in self.inputs.iter() {
+            write_bytes(&mut bin, &input.previous_output.txid);
+            bin.extend(input.previous_output.index.to_be_bytes());
+            write_bytes(&mut bin, &input.public_key);
+            write_bytes(&mut bin, &input.signature);
+        }
+        // 2. The outputs
+        bin.extend((self.outputs.len() as u32).to_be_bytes());
+        for output in self.outputs.iter() {
+            bin.extend(output.value.to_be_bytes());
+            write_bytes(&mut bin, &output.address);
+        }
+        // 3. The coinbase height
+        bin.extend(self.coinbase_height.to_be_bytes());
+        bin
+    }
+
+    fn deserialization(bytes: Vec<u8>) -> Result<UtxoTransaction, DecodeError> {
+        let mut pos = 0;
+        if read_u8(&bytes, &mut pos)? != UtxoTransaction::TAG {
+            return Err(DecodeError::Invalid("not a UTXO transaction"));
+        }
+        // Counts are not trusted for allocating, every element reads at least a few bytes
+        // so a huge count runs out of input
+        let input_count = read_u32(&bytes, &mut pos)?;
+        let mut inputs = Vec::<TxInput>::new();
+        for _ in 0..input_count {
+            let txid = read_bytes(&bytes, &mut pos, MAX_FIELD_LENGTH)?;
+            let index = read_u32(&bytes, &mut pos)?;
+            inputs.push(TxInput {
+                previous_output: OutPoint::new(txid, index),
+                public_key: read_bytes(&bytes, &mut pos, MAX_FIELD_LENGTH)?,
+                signature: read_bytes(&bytes, &mut pos, MAX_FIELD_LENGTH)?,
+            });
+        }
+        let output_count = read_u32(&bytes, &mut pos)?;
+        let mut outputs = Vec::<TxOutput>::new();
+        for _ in 0..output_count {
+            let value = read_u64(&bytes, &mut pos)?;
+            outputs.push(TxOutput::new(
+                value,
+                read_bytes(&bytes, &mut pos, MAX_FIELD_LENGTH)?,
+            ));
+        }
+        let coinbase_height = read_u64(&bytes, &mut pos)?;
+        finish(&bytes, pos)?;
+
+        Ok(UtxoTransaction {
+            inputs,
+            outputs,
+            coinbase_height,
+        })
+    }
+}
+
+impl Display for UtxoTransaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:?}", "-".repeat(40))?;
+        if self.is_coinbase() {
+            writeln!(f, "Coinbase at height: {}", self.coinbase_height)?;
+        }
+        for input in self.inputs.iter() {
+            writeln!(
+                f,
+                "Input: {}:{}",
+                hex::encode(&input.previous_output.txid),
+                input.previous_output.index
+            )?;
+        }
+        for output in self.outputs.iter() {
+            writeln!(
+                f,
+                "Output: {:?} to {:?}",
+                output.value,
+                String::from_utf8_lossy(&output.address)
+            )?;
+        }
+        write!(f, " {}", "-".repeat(40))
+    }
+}
+
+/// Every output of the active chain that has not been spent yet.
+#[derive(Debug, Clone, Default)]
+pub struct UtxoSet {
+    outputs: HashMap<OutPoint, TxOutput>,
+    // Outputs of each address, so looking them up does not walk the whole set
+    by_address: HashMap<Vec<u8>, BTreeSet<OutPoint>>,
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        UtxoSet::default()
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&TxOutput> {
+        self.outputs.get(outpoint)
+    }
+
+    pub fn contains(&self, outpoint: &OutPoint) -> bool {
+        self.outputs.contains_key(outpoint)
+    }
+
+    pub fn insert(&mut self, outpoint: OutPoint, output: TxOutput) {
+        self.remove(&outpoint);
+        self.by_address
+            .entry(output.address.clone())
+            .or_default()
+            .insert(outpoint.clone());
+        self.outputs.insert(outpoint, output);
+    }
+
+    pub fn remove(&mut self, outpoint: &OutPoint) -> Option<TxOutput> {
+        let output = self.outputs.remove(outpoint)?;
+        self.forget(&output.address, outpoint);
+        Some(output)
+    }
+
+    fn forget(&mut self, address: &[u8], outpoint: &OutPoint) {
+        if let Some(outpoints) = self.by_address.get_mut(address) {
+            outpoints.remove(outpoint);
+            if outpoints.is_empty() {
+                self.by_address.remove(address);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.outputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outputs.is_empty()
+    }
+
+    // Unspent outputs paying to `address`, ordered by outpoint
+    pub fn outputs_of(&self, address: &[u8]) -> Vec<(&OutPoint, &TxOutput)> {
+        self.by_address
+            .get(address)
+            .into_iter()
+            .flatten()
+            .filter_map(|outpoint| self.outputs.get_key_value(outpoint))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction() -> UtxoTransaction {
+        let mut input = TxInput::new(OutPoint::new(vec![1; 32], 3));
+        input.public_key = vec![4; 64];
+        input.signature = vec![9; 64];
+        UtxoTransaction::new(
+            vec![input],
+            vec![
+                TxOutput::new(10, b"alice".to_vec()),
+                TxOutput::new(20, b"bob".to_vec()),
+            ],
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = transaction().serialization();
+        let back = UtxoTransaction::deserialization(bytes.clone()).unwrap();
+        assert_eq!(back.serialization(), bytes);
+    }
+
+    #[test]
+    fn every_truncation_fails() {
+        let bytes = transaction().serialization();
+        for length in 0..bytes.len() {
+            assert!(UtxoTransaction::deserialization(bytes[..length].to_vec()).is_err());
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_fail() {
+        let mut bytes = transaction().serialization();
+        bytes.extend([0, 0]);
+        assert_eq!(
+            UtxoTransaction::deserialization(bytes).err(),
+            Some(DecodeError::TrailingBytes(2))
+        );
+    }
+
+    #[test]
+    fn output_value_overflow_is_detected() {
+        let tx = UtxoTransaction::new(
+            Vec::new(),
+            vec![
+                TxOutput::new(u64::MAX, b"alice".to_vec()),
+                TxOutput::new(21, b"bob".to_vec()),
+            ],
+        );
+        assert_eq!(tx.output_value(), None);
+        assert_eq!(transaction().output_value(), Some(30));
+    }
+
+    #[test]
+    fn outputs_of_follows_inserts_and_removals() {
+        let mut set = UtxoSet::new();
+        let (first, second) = (OutPoint::new(vec![1], 0), OutPoint::new(vec![2], 0));
+        set.insert(second.clone(), TxOutput::new(10, b"alice".to_vec()));
+        set.insert(first.clone(), TxOutput::new(20, b"alice".to_vec()));
+        let outpoints: Vec<&OutPoint> = set
+            .outputs_of(b"alice")
+            .into_iter()
+            .map(|(outpoint, _)| outpoint)
+            .collect();
+        assert_eq!(outpoints, vec![&first, &second]);
+
+        set.insert(first.clone(), TxOutput::new(20, b"bob".to_vec()));
+        assert_eq!(set.outputs_of(b"alice").len(), 1);
+        assert_eq!(set.outputs_of(b"bob").len(), 1);
+        set.remove(&second);
+        assert!(set.outputs_of(b"alice").is_empty());
+    }
+}
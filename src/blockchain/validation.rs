@@ -0,0 +1,354 @@
+use crate::blockchain::encoding::DecodeError;
+use crate::blockchain::mempool::MempoolRejection;
+use std::fmt::Display;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChainValidationError {
+    EmptyChain,
+    InvalidGenesis,
+    UnsupportedVersion(u32),
+    MerkleRootMismatch,
+    // The same transaction twice would leave the merkle root unchanged
+    DuplicateTransaction {
+        txid: Vec<u8>,
+    },
+    BlockTooLarge {
+        size: usize,
+        limit: usize,
+    },
+    UnknownParent,
+    PreviousHashMismatch {
+        expected: Vec<u8>,
+        found: Vec<u8>,
+    },
+    UnexpectedDifficulty {
+        expected: u32,
+        found: u32,
+    },
+    InsufficientProofOfWork {
+        hash: Vec<u8>,
+    },
+    // Not later than the median timestamp of the blocks before it
+    TimestampTooOld {
+        median: u128,
+        found: u128,
+    },
+    // Further ahead of the local clock than the network allows
+    TimestampTooFarAhead {
+        limit: u128,
+        found: u128,
+    },
+    NegativeBalance {
+        address: Vec<u8>,
+        balance: i64,
+    },
+    UnexpectedNonce {
+        address: Vec<u8>,
+        expected: u64,
+        found: u64,
+    },
+    // An account transaction whose key does not own the sender's address or did not sign it
+    InvalidSignature {
+        address: Vec<u8>,
+    },
+    // A coinbase anywhere but first in its block, or stamped with the wrong height
+    UnexpectedCoinbase,
+    MissingOutput {
+        txid: Vec<u8>,
+        index: u32,
+    },
+    DoubleSpend {
+        txid: Vec<u8>,
+        index: u32,
+    },
+    InvalidInputSignature {
+        txid: Vec<u8>,
+        index: u32,
+    },
+    OutputsExceedInputs {
+        inputs: u64,
+        outputs: u64,
+    },
+    // An amount, or a sum of them, larger than a balance can hold
+    ValueOverflow,
+    // The coinbase pays more than the block reward plus the fees of the block
+    ExcessiveCoinbase {
+        allowed: u64,
+        found: u64,
+    },
+    // Transaction number `index` of the block does not decode
+    MalformedTransaction {
+        index: usize,
+        reason: DecodeError,
+    },
+}
+
+impl Display for ChainValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChainValidationError::EmptyChain => write!(f, "the chain has no blocks"),
+            ChainValidationError::InvalidGenesis => {
+                write!(
+                    f,
+                    "the genesis block does not match the chain's initial state"
+                )
+            }
+            ChainValidationError::UnsupportedVersion(version) => {
+                write!(f, "unsupported block version {}", version)
+            }
+            ChainValidationError::MerkleRootMismatch => {
+                write!(f, "the merkle root does not match the block's transactions")
+            }
+            ChainValidationError::DuplicateTransaction { txid } => {
+                write!(
+                    f,
+                    "the block carries transaction {} twice",
+                    hex::encode(txid)
+                )
+            }
+            ChainValidationError::BlockTooLarge { size, limit } => write!(
+                f,
+                "the block carries {} bytes of transactions, more than the limit of {}",
+                size, limit
+            ),
+            ChainValidationError::UnknownParent => {
+                write!(f, "the block does not build on any known block")
+            }
+            ChainValidationError::PreviousHashMismatch { expected, found } => write!(
+                f,
+                "previous hash mismatch, expected: {} found: {}",
+                hex::encode(expected),
+                hex::encode(found)
+            ),
+            ChainValidationError::UnexpectedDifficulty { expected, found } => write!(
+                f,
+                "block was mined at bits {:#010x} but the retarget rules require {:#010x}",
+                found, expected
+            ),
+            ChainValidationError::InsufficientProofOfWork { hash } => write!(
+                f,
+                "block hash {} does not meet the proof of work target",
+                hex::encode(hash)
+            ),
+            ChainValidationError::TimestampTooOld { median, found } => write!(
+                f,
+                "block timestamp {} is not later than the median {} of the blocks before it",
+                found, median
+            ),
+            ChainValidationError::TimestampTooFarAhead { limit, found } => write!(
+                f,
+                "block timestamp {} is ahead of the latest allowed {}",
+                found, limit
+            ),
+            ChainValidationError::NegativeBalance { address, balance } => write!(
+                f,
+                "address {} ends up with a negative balance of {}",
+                String::from_utf8_lossy(address),
+                balance
+            ),
+            ChainValidationError::UnexpectedNonce {
+                address,
+                expected,
+                found,
+            } => write!(
+                f,
+                "transaction from {} has nonce {} but the next one is {}",
+                String::from_utf8_lossy(address),
+                found,
+                expected
+            ),
+            ChainValidationError::InvalidSignature { address } => write!(
+                f,
+                "transaction from {} is not signed by the owner of the address",
+                String::from_utf8_lossy(address)
+            ),
+            ChainValidationError::UnexpectedCoinbase => write!(
+                f,
+                "a coinbase transaction has to come first in its block and carry the block height"
+            ),
+            ChainValidationError::MissingOutput { txid, index } => write!(
+                f,
+                "output {}:{} is unknown or already spent",
+                hex::encode(txid),
+                index
+            ),
+            ChainValidationError::DoubleSpend { txid, index } => write!(
+                f,
+                "output {}:{} is spent more than once",
+                hex::encode(txid),
+                index
+            ),
+            ChainValidationError::InvalidInputSignature { txid, index } => write!(
+                f,
+                "the input spending {}:{} is not signed by the owner of the output",
+                hex::encode(txid),
+                index
+            ),
+            ChainValidationError::OutputsExceedInputs { inputs, outputs } => write!(
+                f,
+                "outputs worth {} spend more than the {} held by the inputs",
+                outputs, inputs
+            ),
+            ChainValidationError::ValueOverflow => {
+                write!(
+                    f,
+                    "the amounts add up to more than the largest possible value"
+                )
+            }
+            ChainValidationError::ExcessiveCoinbase { allowed, found } => write!(
+                f,
+                "the coinbase pays {} but at most {} is allowed",
+                found, allowed
+            ),
+            ChainValidationError::MalformedTransaction { index, reason } => {
+                write!(f, "transaction {} is malformed: {}", index, reason)
+            }
+        }
+    }
+}
+
+/// Why `BlockChain` turned down a transaction or could not add a block.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockChainError {
+    // The miner's own address tried to send money
+    SelfSend,
+    InvalidSignature,
+    // A confirmed or pending transaction of the sender already uses this nonce
+    NonceAlreadyUsed {
+        nonce: u64,
+    },
+    // The nonce skips ahead of the sender's next one
+    NonceTooHigh {
+        expected: u64,
+        found: u64,
+    },
+    // What the sender holds, once the pool is mined, does not cover the amount and fee
+    InsufficientBalance {
+        available: i64,
+        required: u64,
+    },
+    // An output the transaction spends is already spent by a transaction in the pool
+    ConflictingSpend {
+        txid: Vec<u8>,
+        index: u32,
+    },
+    // The transaction breaks a consensus rule
+    InvalidTransaction(ChainValidationError),
+    // The pool would not take the transaction
+    Mempool(MempoolRejection),
+    InvalidBlock {
+        height: usize,
+        reason: ChainValidationError,
+    },
+    MiningCancelled,
+    // The block store could not be written, the chain is left as it was
+    Storage(String),
+}
+
+impl Display for BlockChainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockChainError::SelfSend => write!(f, "the miner cannot send money to themselves"),
+            BlockChainError::InvalidSignature => {
+                write!(f, "the transaction is not signed by the sender")
+            }
+            BlockChainError::NonceAlreadyUsed { nonce } => {
+                write!(
+                    f,
+                    "the transaction was already sent, nonce {} is used",
+                    nonce
+                )
+            }
+            BlockChainError::NonceTooHigh { expected, found } => write!(
+                f,
+                "the transaction has nonce {} but the sender's next one is {}",
+                found, expected
+            ),
+            BlockChainError::InsufficientBalance {
+                available,
+                required,
+            } => write!(
+                f,
+                "the sender needs {} but only has {} left",
+                required, available
+            ),
+            BlockChainError::ConflictingSpend { txid, index } => write!(
+                f,
+                "output {}:{} is already spent by a transaction in the pool",
+                hex::encode(txid),
+                index
+            ),
+            BlockChainError::InvalidTransaction(reason) => {
+                write!(f, "invalid transaction: {}", reason)
+            }
+            BlockChainError::Mempool(reason) => write!(f, "{}", reason),
+            BlockChainError::InvalidBlock { height, reason } => {
+                write!(f, "invalid block at height {}: {}", height, reason)
+            }
+            BlockChainError::MiningCancelled => write!(f, "mining was cancelled"),
+            BlockChainError::Storage(reason) => write!(f, "the block store failed: {}", reason),
+        }
+    }
+}
+
+impl From<MempoolRejection> for BlockChainError {
+    fn from(reason: MempoolRejection) -> Self {
+        BlockChainError::Mempool(reason)
+    }
+}
+
+impl From<std::io::Error> for BlockChainError {
+    fn from(error: std::io::Error) -> Self {
+        BlockChainError::Storage(error.to_string())
+    }
+}
+
+/// Outcome of walking the whole chain with `BlockChain::validate_chain`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainValidationReport {
+    // Number of blocks that passed every check before the first failure
+    pub blocks_checked: usize,
+    pub first_bad_height: Option<usize>,
+    pub reason: Option<ChainValidationError>,
+}
+
+impl ChainValidationReport {
+    pub fn valid(blocks_checked: usize) -> Self {
+        ChainValidationReport {
+            blocks_checked,
+            first_bad_height: None,
+            reason: None,
+        }
+    }
+
+    pub fn invalid(height: usize, reason: ChainValidationError) -> Self {
+        ChainValidationReport {
+            blocks_checked: height,
+            first_bad_height: Some(height),
+            reason: Some(reason),
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.reason.is_none()
+    }
+
+    // The number of valid blocks, or the first invalid one as an error
+    pub fn into_result(self) -> Result<usize, BlockChainError> {
+        match (self.first_bad_height, self.reason) {
+            (Some(height), Some(reason)) => Err(BlockChainError::InvalidBlock { height, reason }),
+            _ => Ok(self.blocks_checked),
+        }
+    }
+}
+
+impl Display for ChainValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.first_bad_height, &self.reason) {
+            (Some(height), Some(reason)) => {
+                write!(f, "Invalid block at height {}: {}", height, reason)
+            }
+            _ => write!(f, "Valid chain of {} blocks", self.blocks_checked),
+        }
+    }
+}
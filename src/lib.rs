@@ -0,0 +1,5 @@
+// The chain, the wallet and the HTTP API, usable on their own. The binary only runs the
+// API server on top of them.
+pub mod api_server;
+pub mod blockchain;
+pub mod wallet;
@@ -1,14 +1,12 @@
-mod blockchain;
-mod wallet;
-use blockchain::{block_and_blockchain::BlockChain, transaction};
-use wallet::wallet::Wallet;
-mod api_server;
-use actix_web::middleware::Logger;
-use api_server::api_server::ApiServer;
+use blockchain::api_server::api_server::ApiServer;
+use blockchain::blockchain::chain_params::ChainParams;
 
 #[actix_web::main]
 async fn main() {
     env_logger::init();
-    let server = ApiServer::new(3000);
+    // NETWORK picks the network to join, mainnet unless told otherwise
+    let network = std::env::var("NETWORK").unwrap_or_else(|_| "mainnet".to_string());
+    let params = ChainParams::from_name(&network).expect("Unknown network");
+    let server = ApiServer::new(3000, params);
     server.run().await;
 }
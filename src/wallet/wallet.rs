@@ -1,3 +1,6 @@
+use crate::blockchain::chain_params::ChainParams;
+use crate::blockchain::transaction::Transaction as AccountTransaction;
+use crate::blockchain::utxo::UtxoTransaction;
 use bs58;
 use p256::ecdsa::{
     signature::{Signer, Verifier},
@@ -5,59 +8,65 @@ use p256::ecdsa::{
 };
 use rand_core::OsRng;
 use ripemd160::{Digest as RipemdDigest, Ripemd160};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-use crate::blockchain::transaction;
 pub struct Wallet {
     pub signing_key: SigningKey,
     pub verifying_key: VerifyingKey,
     address: String,
 }
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub sender: String,
     pub recipient: String,
     pub amount: u64,
+    pub fee: u64,
+    // Sequence number of the sender's transactions, see `BlockChain::next_nonce`
+    pub nonce: u64,
     pub public_key: String,
     pub signature: String,
 }
 
+impl Transaction {
+    // The transaction as blocks carry it, None if the key or the signature is not hex
+    pub fn to_account_transaction(&self) -> Option<AccountTransaction> {
+        let mut transaction = AccountTransaction::new(
+            self.sender.as_bytes().to_vec(),
+            self.recipient.as_bytes().to_vec(),
+            self.amount,
+            self.fee,
+            self.nonce,
+        );
+        transaction.public_key = hex::decode(&self.public_key).ok()?;
+        transaction.signature = hex::decode(&self.signature).ok()?;
+        Some(transaction)
+    }
+}
+
 impl Wallet {
-    pub fn new() -> Self {
-        let signing_key = SigningKey::random(&mut OsRng);
+    pub fn new(params: &ChainParams) -> Self {
+        Wallet::from_signing_key(SigningKey::random(&mut OsRng), params)
+    }
+
+    // The wallet of the hex encoded private key, as `private_key_str` writes it
+    pub fn from_private_key(private_key: &str, params: &ChainParams) -> Option<Self> {
+        let bytes = hex::decode(private_key.trim()).ok()?;
+        let signing_key = SigningKey::from_slice(&bytes).ok()?;
+        Some(Wallet::from_signing_key(signing_key, params))
+    }
+
+    fn from_signing_key(signing_key: SigningKey, params: &ChainParams) -> Self {
         let verifying_key = VerifyingKey::from(&signing_key);
+        let key_points = verifying_key.to_encoded_point(false);
         let mut address = String::new();
-        let mut gen_address = || {
-            let key_points = verifying_key.to_encoded_point(false);
-            if let (Some(x), Some(y)) = (key_points.x(), key_points.y()) {
-                let mut pub_key_bytes = Vec::with_capacity(x.len() + y.len());
-                pub_key_bytes.extend_from_slice(x);
-                pub_key_bytes.extend_from_slice(y);
-                // sha256 on the public key
-                let hash = Sha256::digest(&pub_key_bytes);
-                // Ripemd160 on the hash
-                let mut hasher = Ripemd160::new();
-                hasher.update(&hash);
-                let mut hash_result = hasher.finalize().to_vec();
-                // Add byte version in front of the Ripemd160 hash result (0x00 for mainnet)
-                hash_result.insert(0, 0x00);
-                // Do Sha256 on the previous result
-                let hash2 = Sha256::digest(&hash_result);
-                // Do Sha256 on the previous result
-                let hash3 = Sha256::digest(&hash2);
-                // Take the first 4 bytes of the previous result as checksum
-                let checksum = &hash3[0..4];
-                // Add the checksum to the end of the extended ripemd160 hash result
-                let full_hash = [hash_result, checksum.to_vec()].concat();
-                // Base 58 encoding
-                address = bs58::encode(full_hash).into_string();
-            } else {
-                // Do nothing
-            }
-        };
-        gen_address();
+        if let (Some(x), Some(y)) = (key_points.x(), key_points.y()) {
+            let mut pub_key_bytes = Vec::with_capacity(x.len() + y.len());
+            pub_key_bytes.extend_from_slice(x);
+            pub_key_bytes.extend_from_slice(y);
+            address = Wallet::address_from_public_key(&pub_key_bytes, params.address_version);
+        }
         Self {
             signing_key,
             verifying_key,
@@ -65,6 +74,28 @@ impl Wallet {
         }
     }
 
+    // Address of the x || y public key, as found in `Transaction.public_key` once hex decoded
+    pub fn address_from_public_key(pub_key_bytes: &[u8], version: u8) -> String {
+        // sha256 on the public key
+        let hash = Sha256::digest(pub_key_bytes);
+        // Ripemd160 on the hash
+        let mut hasher = Ripemd160::new();
+        hasher.update(hash);
+        let mut hash_result = hasher.finalize().to_vec();
+        // Add byte version in front of the Ripemd160 hash result (0x00 for mainnet)
+        hash_result.insert(0, version);
+        // Do Sha256 on the previous result
+        let hash2 = Sha256::digest(&hash_result);
+        // Do Sha256 on the previous result
+        let hash3 = Sha256::digest(hash2);
+        // Take the first 4 bytes of the previous result as checksum
+        let checksum = &hash3[0..4];
+        // Add the checksum to the end of the extended ripemd160 hash result
+        let full_hash = [hash_result, checksum.to_vec()].concat();
+        // Base 58 encoding
+        bs58::encode(full_hash).into_string()
+    }
+
     pub fn private_key_str(&self) -> String {
         // 1. Convert private key into hex string
         hex::encode(self.signing_key.to_bytes())
@@ -85,48 +116,151 @@ impl Wallet {
         self.address.clone()
     }
 
-    pub fn sign_transaction(&self, receiver: &String, amount: u64) -> Transaction {
+    pub fn sign_transaction(
+        &self,
+        receiver: &str,
+        amount: u64,
+        fee: u64,
+        nonce: u64,
+    ) -> Transaction {
         let mut transaction = Transaction {
             sender: self.address.clone(),
-            recipient: receiver.clone(),
+            recipient: receiver.to_string(),
             amount,
+            fee,
+            nonce,
             signature: String::new(),
             public_key: self.public_key_str(),
         };
 
-        let serialized_str = serde_json::to_string(&transaction).unwrap();
-        let serialized = serialized_str.as_bytes();
-        let sig: Signature = self.signing_key.sign(serialized);
+        // The signature covers the transaction exactly as blocks carry it
+        let signing_bytes = transaction
+            .to_account_transaction()
+            .expect("Error encoding our own key")
+            .signing_bytes();
+        let sig = self.sign(&signing_bytes);
         transaction.signature = hex::encode(sig.to_bytes());
         transaction
     }
 
-    pub fn verify_transaction(transaction: &Transaction) -> bool {
-        let signature_str = transaction.signature.clone();
-        let signature_bin = hex::decode(signature_str).unwrap();
-        let mut transaction_clone = transaction.clone();
-        transaction_clone.signature = String::new();
+    // Signs every input of `transaction` that has no key yet or carries ours. Inputs owned by
+    // other wallets need their keys filled in before anyone signs, the keys are signed too.
+    pub fn sign_utxo_transaction(&self, transaction: &mut UtxoTransaction) {
+        let public_key = hex::decode(self.public_key_str()).unwrap();
+        for input in transaction.inputs.iter_mut() {
+            if input.public_key.is_empty() {
+                input.public_key = public_key.clone();
+            }
+        }
+        let signing_bytes = transaction.signing_bytes();
+        let sig = self.sign(&signing_bytes);
+        for input in transaction.inputs.iter_mut() {
+            if input.public_key == public_key {
+                input.signature = sig.to_bytes().to_vec();
+            }
+        }
+    }
 
-        let serialized_str = serde_json::to_string(&transaction_clone).unwrap();
-        let serialized = serialized_str.as_bytes();
+    // Both s and n - s make a valid signature, and the ids of transactions cover their
+    // signatures. Only the low one is accepted, so nobody can change the id of a
+    // transaction on its way into a block.
+    fn sign(&self, message: &[u8]) -> Signature {
+        let sig: Signature = self.signing_key.sign(message);
+        sig.normalize_s().unwrap_or(sig)
+    }
 
-        // Convert the signature from string to the instance of the struct
+    // Checks the transaction is signed by the key it carries and that the key owns the
+    // sender's address
+    pub fn verify_transaction(transaction: &Transaction, address_version: u8) -> bool {
+        // Transactions come from clients, anything malformed is just an invalid signature
+        transaction
+            .to_account_transaction()
+            .is_some_and(|transaction| transaction.verify(address_version))
+    }
+
+    // Checks `signature` is the signature of `message` by the x || y `public_key`
+    pub fn verify_signature(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
         // We need to make sure that the binary data is 64 bytes long
-        let sig_array: [u8; 64] = signature_bin.try_into().unwrap();
-        let signature = match Signature::from_bytes(&sig_array.into()) {
-            Ok(sig) => sig,
-            Err(e) => {
-                println!("Error getting signature: {:?}", e);
-                return false;
-            }
+        let Ok(sig_array) = <[u8; 64]>::try_from(signature) else {
+            return false;
         };
-        // Convert the binary data into the VerifyingKey object
-        let public_key_str = transaction_clone.public_key.clone();
-        let mut public_key_bin = hex::decode(public_key_str).unwrap();
+        let Ok(signature) = Signature::from_bytes(&sig_array.into()) else {
+            return false;
+        };
+        // A high s is the malleated form of a signature we would have made low
+        if signature.normalize_s().is_some() {
+            return false;
+        }
         // Making sure the binary data is in sec1 format: [0x04 || x coordinate || y coordinate]
+        let mut public_key_bin = vec![0x04];
+        public_key_bin.extend(public_key);
+        match VerifyingKey::from_sec1_bytes(&public_key_bin) {
+            Ok(public_key) => public_key.verify(message, &signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_are_low_s() {
+        let params = ChainParams::regtest();
+        let wallet = Wallet::new(&params);
+        for nonce in 0..16 {
+            let transaction = wallet.sign_transaction("bob", 5, 1, nonce);
+            let sig_bytes = hex::decode(&transaction.signature).unwrap();
+            let sig = Signature::from_slice(&sig_bytes).unwrap();
+            assert!(sig.normalize_s().is_none());
+            assert!(Wallet::verify_transaction(
+                &transaction,
+                params.address_version
+            ));
+        }
+    }
+
+    #[test]
+    fn malleated_signature_is_rejected() {
+        let params = ChainParams::regtest();
+        let wallet = Wallet::new(&params);
+        let mut transaction = wallet.sign_transaction("bob", 5, 1, 0);
+        let sig_bytes = hex::decode(&transaction.signature).unwrap();
+        let sig = Signature::from_slice(&sig_bytes).unwrap();
+        // (r, n - s) verifies against the same key and message
+        let malleated = Signature::from_scalars(sig.r(), -sig.s()).unwrap();
+        let public_key = hex::decode(&transaction.public_key).unwrap();
+        let mut public_key_bin = vec![0x04];
+        public_key_bin.extend(&public_key);
+        let message = transaction
+            .to_account_transaction()
+            .unwrap()
+            .signing_bytes();
+        assert!(VerifyingKey::from_sec1_bytes(&public_key_bin)
+            .unwrap()
+            .verify(&message, &malleated)
+            .is_ok());
+
+        transaction.signature = hex::encode(malleated.to_bytes());
+        assert!(!Wallet::verify_transaction(
+            &transaction,
+            params.address_version
+        ));
+        assert!(!Wallet::verify_signature(
+            &public_key,
+            &message,
+            &malleated.to_bytes()
+        ));
+    }
 
-        public_key_bin.insert(0, 0x04);
-        let public_key = VerifyingKey::from_sec1_bytes(&public_key_bin).unwrap();
-        public_key.verify(serialized, &signature).is_ok()
+    #[test]
+    fn private_key_round_trip() {
+        let params = ChainParams::regtest();
+        let wallet = Wallet::new(&params);
+        let restored = Wallet::from_private_key(&wallet.private_key_str(), &params).unwrap();
+        assert_eq!(restored.get_address(), wallet.get_address());
+        assert!(Wallet::from_private_key("not hex", &params).is_none());
+        assert!(Wallet::from_private_key("00", &params).is_none());
     }
 }